@@ -1,29 +1,141 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    mem,
+    rc::Rc,
+};
+
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
-use crate::virtual_machine::{chunk::Chunk, op_code::OpCode, value::Value};
+use crate::virtual_machine::{
+    chunk::Chunk,
+    function::{Function, Parameter},
+    op_code::OpCode,
+    value::{Value, Variant, RESULT_ENUM, RUNTIME_ERROR_ENUM},
+};
 
 use super::{
     scanner::{Scanner, ScannerError},
-    token::{Token, TokenKind},
+    token::{LiteralValue, Token, TokenKind},
 };
 
+/// Compiles `source`, leaving out the warnings `compile_with_warnings`
+/// gives back as well.
+pub fn compile(source: &str) -> Result<Chunk, CompilerError> {
+    compile_with_warnings(source).map(|(chunk, _)| chunk)
+}
+
+pub fn compile_with_warnings(source: &str) -> Result<(Chunk, Vec<CompilerWarning>), CompilerError> {
+    let mut compiler = Compiler::new(Scanner::new(source));
+    compiler.compile()?;
+    Ok((compiler.compiling_chunk, compiler.warnings))
+}
+
+const LOCALS_MAX: usize = u8::MAX as usize + 1;
+
+struct Local<'source> {
+    name: &'source str,
+    /// Where the local lives on the stack, relative to the frame.
+    slot: u8,
+    /// `None` while the local's own initializer is being compiled.
+    depth: Option<usize>,
+    constant: bool,
+}
+
+enum Pattern<'source> {
+    Wildcard,
+    Binding(&'source str),
+    Literal(Value),
+    /// `lower..upper`, excluding `upper`.
+    Range(Value, Value),
+    /// A list, or a tuple if `tuple` is set.
+    Sequence {
+        tuple: bool,
+        elements: Vec<Pattern<'source>>,
+        /// Where `..rest` appears among the elements and the name it binds.
+        rest: Option<(usize, Option<&'source str>)>,
+    },
+    /// `Enum.Variant(fields)`. Without parentheses the payload isn't looked
+    /// at, so `fields` is empty.
+    Variant {
+        variant: Rc<Variant>,
+        fields: Vec<Pattern<'source>>,
+    },
+}
+
+impl Pattern<'_> {
+    /// Whether the pattern matches every value.
+    fn is_irrefutable(&self) -> bool {
+        matches!(self, Pattern::Wildcard | Pattern::Binding(_))
+    }
+}
+
+/// How to get from a `match` subject to the part of it a pattern looks at.
+#[derive(Clone, Copy)]
+enum PathStep {
+    Index(i64),
+    /// Drop this many elements from the front and back of a list or tuple.
+    Slice(u8, u8),
+    Payload(u8),
+}
+
+/// The parts of the compiler's state that belong to the function being
+/// compiled.
+struct FunctionState<'source> {
+    chunk: Chunk,
+    locals: Vec<Local<'source>>,
+    scope_depth: usize,
+    stack_depth: usize,
+}
+
+impl FunctionState<'_> {
+    /// A function's parameters are in its outermost scope, above the
+    /// function itself in slot 0.
+    fn new(scope_depth: usize) -> Self {
+        Self {
+            chunk: Chunk::new(),
+            locals: Vec::new(),
+            scope_depth,
+            stack_depth: 1,
+        }
+    }
+}
+
 struct Compiler<'source> {
     compiling_chunk: Chunk,
     scanner: Scanner<'source>,
+    previous: Option<Token<'source>>,
+    locals: Vec<Local<'source>>,
+    scope_depth: usize,
+    /// How many values the emitted code has on the stack at this point.
+    stack_depth: usize,
+    constant_globals: HashSet<&'source str>,
+    /// Enums only exist at compile time, as the variants that can be built
+    /// and matched.
+    enums: HashMap<&'source str, Vec<Rc<Variant>>>,
+    /// The names of the locals of the functions the one being compiled is
+    /// nested in, which it can't reach until there are closures.
+    enclosing_locals: Vec<Vec<&'source str>>,
+    warnings: Vec<CompilerWarning>,
 }
 
 #[repr(u8)]
 #[derive(IntoPrimitive, TryFromPrimitive, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Precedence {
     None,
-    Assignment, // =
+    Assignment, // = += -= *= /= %=
     Or,         // or
     And,        // and
     Equality,   // == !=
     Comparison, // < > <= >=
+    BitOr,      // |
+    BitXor,     // ^
+    BitAnd,     // &
+    Shift,      // << >>
     Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
+    Factor,     // * / ~/ %
+    Unary,      // ! - ~
+    Power,      // **
     Call,       // () .
     Primary,
 }
@@ -34,7 +146,7 @@ impl Precedence {
     }
 }
 
-type ParseFn<'source> = fn(&mut Compiler<'source>) -> Result<(), CompilerError>;
+type ParseFn<'source> = fn(&mut Compiler<'source>, bool) -> Result<(), CompilerError>;
 struct ParseRule<'source> {
     prefix: Option<ParseFn<'source>>,
     infix: Option<ParseFn<'source>>,
@@ -52,18 +164,54 @@ impl<'source> ParseRule<'source> {
             Option<ParseFn<'source>>,
             Precedence,
         ) = match kind {
-            TokenKind::LeftParen => (Some(Compiler::grouping), None, Precedence::None),
+            TokenKind::LeftParen => (
+                Some(Compiler::grouping),
+                Some(Compiler::call),
+                Precedence::Call,
+            ),
+            TokenKind::LeftBrace => (
+                Some(Compiler::list),
+                Some(Compiler::index),
+                Precedence::Call,
+            ),
+            TokenKind::LeftCurly => (Some(Compiler::block_expression), None, Precedence::None),
+            TokenKind::If => (Some(Compiler::if_expression), None, Precedence::None),
+            TokenKind::Match => (Some(Compiler::match_expression), None, Precedence::None),
+            TokenKind::Try => (Some(Compiler::try_expression), None, Precedence::None),
 
             TokenKind::Minus => (
                 Some(Compiler::unary),
                 Some(Compiler::binary),
-                Precedence::None,
+                Precedence::Term,
             ),
             TokenKind::Plus => (None, Some(Compiler::binary), Precedence::Term),
             TokenKind::Slash => (None, Some(Compiler::binary), Precedence::Factor),
             TokenKind::Star => (None, Some(Compiler::binary), Precedence::Factor),
+            TokenKind::Percent => (None, Some(Compiler::binary), Precedence::Factor),
+            TokenKind::TildeSlash => (None, Some(Compiler::binary), Precedence::Factor),
+            TokenKind::StarStar => (None, Some(Compiler::binary), Precedence::Power),
+            TokenKind::Tilde => (Some(Compiler::unary), None, Precedence::None),
+            TokenKind::Bang => (Some(Compiler::unary), None, Precedence::None),
+            TokenKind::Question => (None, Some(Compiler::propagate), Precedence::Call),
+            TokenKind::BangEqual => (None, Some(Compiler::binary), Precedence::Equality),
+            TokenKind::EqualEqual => (None, Some(Compiler::binary), Precedence::Equality),
+            TokenKind::Greater => (None, Some(Compiler::binary), Precedence::Comparison),
+            TokenKind::GreaterEqual => (None, Some(Compiler::binary), Precedence::Comparison),
+            TokenKind::Less => (None, Some(Compiler::binary), Precedence::Comparison),
+            TokenKind::LessEqual => (None, Some(Compiler::binary), Precedence::Comparison),
+            TokenKind::And => (None, Some(Compiler::and), Precedence::And),
+            TokenKind::Or => (None, Some(Compiler::or), Precedence::Or),
+            TokenKind::Pipe => (None, Some(Compiler::binary), Precedence::BitOr),
+            TokenKind::Caret => (None, Some(Compiler::binary), Precedence::BitXor),
+            TokenKind::Ampersand => (None, Some(Compiler::binary), Precedence::BitAnd),
+            TokenKind::LessLess => (None, Some(Compiler::binary), Precedence::Shift),
+            TokenKind::GreaterGreater => (None, Some(Compiler::binary), Precedence::Shift),
 
-            TokenKind::Literal => (Some(Compiler::number), None, Precedence::None),
+            TokenKind::Identifier => (Some(Compiler::variable), None, Precedence::None),
+            TokenKind::Literal => (Some(Compiler::literal), None, Precedence::None),
+            TokenKind::True | TokenKind::False | TokenKind::Nil => {
+                (Some(Compiler::keyword_literal), None, Precedence::None)
+            }
             _ => (None, None, Precedence::None),
         };
 
@@ -75,40 +223,766 @@ impl<'source> ParseRule<'source> {
     }
 }
 
-#[allow(dead_code)]
+/// The operator applied by a compound assignment such as `+=`.
+fn compound_operator(kind: TokenKind) -> Option<OpCode> {
+    match kind {
+        TokenKind::PlusEqual => Some(OpCode::Add),
+        TokenKind::MinusEqual => Some(OpCode::Subtract),
+        TokenKind::StarEqual => Some(OpCode::Multiply),
+        TokenKind::SlashEqual => Some(OpCode::Divide),
+        TokenKind::PercentEqual => Some(OpCode::Modulo),
+        _ => None,
+    }
+}
+
+fn is_assignment(kind: TokenKind) -> bool {
+    kind == TokenKind::Equal || compound_operator(kind).is_some()
+}
+
+fn literal_value(literal: &LiteralValue) -> Option<Value> {
+    match literal {
+        LiteralValue::Integer(value) => Some(Value::Integer(*value as i64)),
+        LiteralValue::Float(value) => Some(Value::Float(*value)),
+        LiteralValue::String(value) => Some(Value::String(value.clone())),
+        LiteralValue::None => None,
+    }
+}
+
+fn collect_bindings<'source>(
+    pattern: &Pattern<'source>,
+    path: &mut Vec<PathStep>,
+    bindings: &mut Vec<(&'source str, Vec<PathStep>)>,
+) {
+    match pattern {
+        Pattern::Binding(name) => bindings.push((name, path.clone())),
+        Pattern::Sequence { elements, rest, .. } => {
+            for (i, element) in elements.iter().enumerate() {
+                path.push(PathStep::Index(element_index(i, elements.len(), rest)));
+                collect_bindings(element, path, bindings);
+                path.pop();
+            }
+            if let Some((position, Some(name))) = *rest {
+                let back = elements.len() - position;
+                path.push(PathStep::Slice(position as u8, back as u8));
+                bindings.push((name, path.clone()));
+                path.pop();
+            }
+        }
+        Pattern::Variant { fields, .. } => {
+            for (i, field) in fields.iter().enumerate() {
+                path.push(PathStep::Payload(i as u8));
+                collect_bindings(field, path, bindings);
+                path.pop();
+            }
+        }
+        Pattern::Wildcard | Pattern::Literal(_) | Pattern::Range(..) => {}
+    }
+}
+
+/// Elements after a `..rest` are indexed from the back of the list.
+fn element_index(i: usize, len: usize, rest: &Option<(usize, Option<&str>)>) -> i64 {
+    match *rest {
+        Some((position, _)) if i >= position => -((len - i) as i64),
+        _ => i as i64,
+    }
+}
+
 impl<'source> Compiler<'source> {
     pub fn new(scanner: Scanner<'source>) -> Self {
         Self {
             compiling_chunk: Chunk::new(),
             scanner,
+            previous: None,
+            locals: Vec::new(),
+            scope_depth: 0,
+            stack_depth: 1,
+            constant_globals: HashSet::new(),
+            enclosing_locals: Vec::new(),
+            enums: HashMap::from([
+                (
+                    RESULT_ENUM,
+                    Variant::result_variants().map(Rc::new).to_vec(),
+                ),
+                (
+                    RUNTIME_ERROR_ENUM,
+                    Variant::runtime_error_variants().map(Rc::new).to_vec(),
+                ),
+            ]),
+            warnings: Vec::new(),
         }
     }
 
+    /// A program is a list of declarations, optionally followed by an
+    /// expression without a trailing `;` whose value the program returns.
     pub fn compile(&mut self) -> Result<(), CompilerError> {
-        self.expression()?;
-        // self.consume(TokenKind::Eof);
+        let mut has_value = false;
+
+        while self.peek_kind()?.is_some() {
+            has_value = self.declaration()?;
+        }
+
+        if !has_value {
+            self.emit_op_code(OpCode::Nil);
+        }
+        self.emit_op_code(OpCode::Return);
 
         Ok(())
     }
 
-    fn peek(&mut self) -> Result<Token, CompilerError> {
+    fn peek(&mut self) -> Result<Token<'source>, CompilerError> {
         Ok(self.scanner.peek()?)
     }
 
-    fn consume(&mut self, kind: TokenKind) {
-        todo!()
+    /// The kind of the next token, or `None` at the end of the source.
+    fn peek_kind(&mut self) -> Result<Option<TokenKind>, CompilerError> {
+        match self.scanner.peek() {
+            Ok(token) => Ok(Some(token.kind)),
+            Err(ScannerError::Eof) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn previous(&self) -> &Token<'source> {
+        self.previous
+            .as_ref()
+            .expect("parse functions are only called after a token was consumed")
+    }
+
+    fn advance(&mut self) -> Result<Token<'source>, CompilerError> {
+        let token = self.scanner.next_token()?;
+        self.previous = Some(token.clone());
+        Ok(token)
+    }
+
+    fn check(&mut self, kind: TokenKind) -> Result<bool, CompilerError> {
+        Ok(self.peek_kind()? == Some(kind))
+    }
+
+    fn matches(&mut self, kind: TokenKind) -> Result<bool, CompilerError> {
+        let matched = self.check(kind)?;
+        if matched {
+            self.advance()?;
+        }
+        Ok(matched)
+    }
+
+    fn consume(&mut self, kind: TokenKind) -> Result<Token<'source>, CompilerError> {
+        if self.peek()?.kind == kind {
+            self.advance()
+        } else {
+            Err(CompilerError::ExpectedToken(kind))
+        }
+    }
+
+    /// Returns whether the declaration was a trailing expression whose value
+    /// was left on the stack.
+    fn declaration(&mut self) -> Result<bool, CompilerError> {
+        match self.peek()?.kind {
+            TokenKind::Var => {
+                self.advance()?;
+                self.var_declaration(false)?;
+            }
+            TokenKind::Const => {
+                self.advance()?;
+                self.var_declaration(true)?;
+            }
+            TokenKind::Enum => {
+                self.advance()?;
+                self.enum_declaration()?;
+            }
+            TokenKind::Fn => {
+                self.advance()?;
+                self.function_declaration()?;
+            }
+            _ => return self.statement(),
+        }
+
+        Ok(false)
+    }
+
+    fn var_declaration(&mut self, constant: bool) -> Result<(), CompilerError> {
+        if matches!(
+            self.peek_kind()?,
+            Some(TokenKind::LeftParen | TokenKind::LeftBrace)
+        ) {
+            return self.destructuring_declaration(constant);
+        }
+
+        let name = self.consume(TokenKind::Identifier)?.lexeme;
+
+        if self.scope_depth > 0 {
+            self.declare_local(name, constant)?;
+        } else {
+            self.check_global_name(name)?;
+        }
+
+        if self.matches(TokenKind::Equal)? {
+            self.expression()?;
+        } else if constant {
+            return Err(CompilerError::MissingInitializer);
+        } else {
+            self.emit_op_code(OpCode::Nil);
+        }
+        self.consume(TokenKind::Semicolon)?;
+
+        if self.scope_depth > 0 {
+            self.mark_initialized();
+        } else {
+            if constant {
+                self.constant_globals.insert(name);
+            }
+            let name_index = self.identifier_constant(name);
+            self.emit_constant_instruction(OpCode::DefineGlobal, name_index, &[])?;
+        }
+
+        Ok(())
+    }
+
+    fn check_global_name(&self, name: &str) -> Result<(), CompilerError> {
+        if self.constant_globals.contains(name)
+            || self.enums.contains_key(name)
+            || self.prelude_variant(name).is_some()
+        {
+            return Err(CompilerError::AlreadyDeclared);
+        }
+        Ok(())
+    }
+
+    /// `var (x, y) = point;` or `var [first, ..rest] = xs;`. It's a runtime
+    /// error if the value doesn't match the pattern.
+    fn destructuring_declaration(&mut self, constant: bool) -> Result<(), CompilerError> {
+        let pattern = self.pattern()?;
+        let mut bindings = Vec::new();
+        collect_bindings(&pattern, &mut Vec::new(), &mut bindings);
+
+        self.consume(TokenKind::Equal)?;
+        if self.scope_depth > 0 {
+            self.declare_local("", true)?;
+        }
+        let subject = self.stack_depth as u8;
+        self.expression()?;
+        self.consume(TokenKind::Semicolon)?;
+
+        if self.scope_depth > 0 {
+            self.mark_initialized();
+            self.destructure(&pattern, subject, constant)?;
+            return Ok(());
+        }
+
+        // Globals are defined straight from the value, which is then dropped.
+        self.emit_refutable_check(&pattern, subject)?;
+        for (i, (name, path)) in bindings.iter().enumerate() {
+            if bindings[..i].iter().any(|(other, _)| other == name) {
+                return Err(CompilerError::AlreadyDeclared);
+            }
+            self.check_global_name(name)?;
+            if constant {
+                self.constant_globals.insert(name);
+            }
+            self.emit_path(subject, path)?;
+            let name_index = self.identifier_constant(name);
+            self.emit_constant_instruction(OpCode::DefineGlobal, name_index, &[])?;
+        }
+        self.emit_op_code(OpCode::Pop);
+
+        Ok(())
+    }
+
+    /// Declares a local for each of the pattern's bindings, erroring at
+    /// runtime if `subject` doesn't match.
+    fn destructure(
+        &mut self,
+        pattern: &Pattern<'source>,
+        subject: u8,
+        constant: bool,
+    ) -> Result<(), CompilerError> {
+        self.emit_refutable_check(pattern, subject)?;
+
+        let mut bindings = Vec::new();
+        collect_bindings(pattern, &mut Vec::new(), &mut bindings);
+        for (name, path) in &bindings {
+            self.declare_local(name, constant)?;
+            self.emit_path(subject, path)?;
+            self.mark_initialized();
+        }
+
+        Ok(())
+    }
+
+    /// Emits the pattern's tests, failing with a runtime error if any of
+    /// them fails.
+    fn emit_refutable_check(
+        &mut self,
+        pattern: &Pattern<'source>,
+        subject: u8,
+    ) -> Result<(), CompilerError> {
+        let mut fail_jumps = Vec::new();
+        self.emit_pattern_tests(pattern, subject, &mut Vec::new(), &mut fail_jumps)?;
+        if fail_jumps.is_empty() {
+            return Ok(());
+        }
+
+        let matched_jump = self.emit_jump(OpCode::Jump);
+        for jump in fail_jumps {
+            self.patch_jump(jump)?;
+        }
+        // The failed test's `false` is on the stack.
+        self.stack_depth += 1;
+        self.emit_op_code(OpCode::MatchFailed);
+
+        self.patch_jump(matched_jump)
+    }
+
+    /// `fn name(a, b = 1, (x, y), ..rest) { ... }` returns the value of its
+    /// body unless it returns early. Defaults are evaluated here, when the
+    /// function is declared, and destructured parameters can only be passed
+    /// by position. A local function finds itself in slot 0, where the
+    /// callee is, so it can call itself.
+    fn function_declaration(&mut self) -> Result<(), CompilerError> {
+        let name = self.consume(TokenKind::Identifier)?.lexeme;
+        if self.scope_depth > 0 {
+            self.declare_local(name, false)?;
+        } else {
+            self.check_global_name(name)?;
+        }
+
+        let mut patterns = Vec::new();
+        let mut params = Vec::new();
+        let mut variadic = false;
+        let mut defaults = 0usize;
+
+        self.consume(TokenKind::LeftParen)?;
+        while !self.check(TokenKind::RightParen)? {
+            if self.matches(TokenKind::DotDot)? {
+                let rest = self.consume(TokenKind::Identifier)?.lexeme;
+                patterns.push(Pattern::Binding(rest));
+                params.push(Parameter {
+                    name: Some(rest.into()),
+                    has_default: false,
+                });
+                variadic = true;
+                self.matches(TokenKind::Comma)?;
+                break;
+            }
+
+            let pattern = self.pattern()?;
+            let has_default = self.matches(TokenKind::Equal)?;
+            if has_default {
+                self.expression()?;
+                defaults += 1;
+            }
+            params.push(Parameter {
+                name: match pattern {
+                    Pattern::Binding(name) => Some(name.into()),
+                    _ => None,
+                },
+                has_default,
+            });
+            patterns.push(pattern);
+
+            if !self.matches(TokenKind::Comma)? {
+                break;
+            }
+        }
+        self.consume(TokenKind::RightParen)?;
+
+        if params.len() > u8::MAX as usize {
+            return Err(CompilerError::TooManyElements);
+        }
+
+        let local = self.scope_depth > 0;
+        let enclosing = self.swap_function_state(FunctionState::new(1));
+        self.enclosing_locals
+            .push(enclosing.locals.iter().map(|local| local.name).collect());
+        if local {
+            self.locals.push(Local {
+                name,
+                slot: 0,
+                depth: Some(0),
+                constant: true,
+            });
+        }
+
+        let mut slots = Vec::new();
+        for pattern in &patterns {
+            match pattern {
+                Pattern::Binding(name) => self.declare_local(name, false)?,
+                _ => self.declare_local("", true)?,
+            }
+            slots.push(self.stack_depth as u8);
+            self.stack_depth += 1;
+            self.mark_initialized();
+        }
+        for (pattern, slot) in patterns.iter().zip(slots) {
+            if !matches!(pattern, Pattern::Binding(_)) {
+                self.destructure(pattern, slot, false)?;
+            }
+        }
+
+        self.consume(TokenKind::LeftCurly)?;
+        self.block()?;
+        self.emit_op_code(OpCode::Return);
+
+        let compiled = self.swap_function_state(enclosing);
+        self.enclosing_locals.pop();
+        let function = Function {
+            name: name.into(),
+            chunk: Rc::new(compiled.chunk),
+            params: params.into(),
+            variadic,
+            defaults: Vec::new(),
+        };
+        let function = self.make_constant(Value::Function(Rc::new(function)));
+        self.emit_constant_instruction(OpCode::MakeFunction, function, &[defaults as u8])?;
+
+        if self.scope_depth > 0 {
+            self.mark_initialized();
+        } else {
+            let name_index = self.identifier_constant(name);
+            self.emit_constant_instruction(OpCode::DefineGlobal, name_index, &[])?;
+        }
+
+        Ok(())
+    }
+
+    fn swap_function_state(&mut self, state: FunctionState<'source>) -> FunctionState<'source> {
+        FunctionState {
+            chunk: mem::replace(&mut self.compiling_chunk, state.chunk),
+            locals: mem::replace(&mut self.locals, state.locals),
+            scope_depth: mem::replace(&mut self.scope_depth, state.scope_depth),
+            stack_depth: mem::replace(&mut self.stack_depth, state.stack_depth),
+        }
+    }
+
+    /// `enum Shape { Empty, Circle(radius), Rect(width, height) }`. Field
+    /// names only document the payload, which is positional.
+    fn enum_declaration(&mut self) -> Result<(), CompilerError> {
+        let name = self.consume(TokenKind::Identifier)?.lexeme;
+        if self.enums.contains_key(name) || self.constant_globals.contains(name) {
+            return Err(CompilerError::AlreadyDeclared);
+        }
+
+        self.consume(TokenKind::LeftCurly)?;
+        let mut variants: Vec<Rc<Variant>> = Vec::new();
+        while !self.check(TokenKind::RightCurly)? {
+            let variant = self.consume(TokenKind::Identifier)?.lexeme;
+            if variants.iter().any(|other| &*other.name == variant) {
+                return Err(CompilerError::AlreadyDeclared);
+            }
+
+            let mut arity = 0usize;
+            if self.matches(TokenKind::LeftParen)? {
+                while !self.check(TokenKind::RightParen)? {
+                    self.consume(TokenKind::Identifier)?;
+                    arity += 1;
+                    if !self.matches(TokenKind::Comma)? {
+                        break;
+                    }
+                }
+                self.consume(TokenKind::RightParen)?;
+            }
+
+            variants.push(Rc::new(Variant {
+                enum_name: name.into(),
+                name: variant.into(),
+                arity: u8::try_from(arity).map_err(|_| CompilerError::TooManyElements)?,
+            }));
+
+            if !self.matches(TokenKind::Comma)? {
+                break;
+            }
+        }
+        self.consume(TokenKind::RightCurly)?;
+        self.matches(TokenKind::Semicolon)?;
+
+        self.enums.insert(name, variants);
+
+        Ok(())
+    }
+
+    /// `Ok` and `Err` can be used without naming their enum.
+    fn prelude_variant(&self, name: &str) -> Option<Rc<Variant>> {
+        self.enums[RESULT_ENUM]
+            .iter()
+            .find(|variant| &*variant.name == name)
+            .cloned()
+    }
+
+    /// Looks up `Enum.Variant`, with the enum's name already consumed.
+    fn resolve_variant(&mut self, enum_name: &str) -> Result<Rc<Variant>, CompilerError> {
+        self.consume(TokenKind::Dot)?;
+        let name = self.consume(TokenKind::Identifier)?.lexeme;
+
+        let variants = self
+            .enums
+            .get(enum_name)
+            .ok_or(CompilerError::UnknownEnum)?;
+        variants
+            .iter()
+            .find(|variant| &*variant.name == name)
+            .cloned()
+            .ok_or(CompilerError::UnknownVariant)
+    }
+
+    fn statement(&mut self) -> Result<bool, CompilerError> {
+        match self.peek()?.kind {
+            TokenKind::Print => {
+                self.advance()?;
+                self.print_statement()?;
+            }
+            TokenKind::For => {
+                self.advance()?;
+                self.for_statement()?;
+            }
+            TokenKind::Return => {
+                self.advance()?;
+                self.return_statement()?;
+            }
+            TokenKind::LeftCurly | TokenKind::If | TokenKind::Match | TokenKind::Try => {
+                return self.block_like_statement()
+            }
+            TokenKind::Throw => {
+                self.advance()?;
+                self.expression()?;
+                self.consume(TokenKind::Semicolon)?;
+                self.emit_op_code(OpCode::Throw);
+            }
+            _ => return self.expression_statement(),
+        }
+
+        Ok(false)
+    }
+
+    /// Whether the next token closes the enclosing block or program, making
+    /// the expression before it that block's value.
+    fn at_block_end(&mut self) -> Result<bool, CompilerError> {
+        Ok(matches!(
+            self.peek_kind()?,
+            None | Some(TokenKind::RightCurly)
+        ))
+    }
+
+    /// Blocks and `if`s used as statements don't need a trailing `;`, and
+    /// aren't continued by an operator on the following line.
+    fn block_like_statement(&mut self) -> Result<bool, CompilerError> {
+        match self.advance()?.kind {
+            TokenKind::If => self.if_expression(false)?,
+            TokenKind::Match => self.match_expression(false)?,
+            TokenKind::Try => self.try_expression(false)?,
+            _ => self.block_expression(false)?,
+        }
+
+        if self.at_block_end()? {
+            return Ok(true);
+        }
+
+        self.matches(TokenKind::Semicolon)?;
+        self.emit_op_code(OpCode::Pop);
+
+        Ok(false)
+    }
+
+    /// `for pattern in sequence { ... }` runs the body for each element of a
+    /// list or tuple, destructuring it with the pattern.
+    fn for_statement(&mut self) -> Result<(), CompilerError> {
+        let pattern = self.pattern()?;
+        self.consume(TokenKind::In)?;
+
+        self.begin_scope();
+
+        // `ForIter` expects the sequence followed by the next index.
+        self.declare_local("", true)?;
+        let sequence = self.stack_depth as u8;
+        self.expression()?;
+        self.mark_initialized();
+        self.declare_local("", false)?;
+        self.emit_constant(Value::Integer(0))?;
+        self.mark_initialized();
+
+        let loop_start = self.compiling_chunk.code.len();
+        self.begin_scope();
+
+        self.declare_local("", true)?;
+        let element = self.stack_depth as u8;
+        self.emit_instruction(OpCode::ForIter, &[sequence, u8::MAX, u8::MAX]);
+        let exit_jump = self.compiling_chunk.code.len() - 2;
+        self.mark_initialized();
+
+        self.destructure(&pattern, element, false)?;
+        self.consume(TokenKind::LeftCurly)?;
+        self.block()?;
+        self.emit_op_code(OpCode::Pop);
+
+        self.end_scope();
+        self.emit_loop(loop_start)?;
+        self.patch_jump(exit_jump)?;
+
+        self.end_scope();
+
+        Ok(())
+    }
+
+    fn return_statement(&mut self) -> Result<(), CompilerError> {
+        if self.matches(TokenKind::Semicolon)? {
+            self.emit_op_code(OpCode::Nil);
+        } else {
+            self.expression()?;
+            self.consume(TokenKind::Semicolon)?;
+        }
+        self.emit_op_code(OpCode::Return);
+
+        Ok(())
+    }
+
+    fn print_statement(&mut self) -> Result<(), CompilerError> {
+        self.expression()?;
+        self.consume(TokenKind::Semicolon)?;
+        self.emit_op_code(OpCode::Print);
+
+        Ok(())
+    }
+
+    /// Compiles the rest of a block whose `{` was just consumed, leaving its
+    /// value, or `nil` if it has none, on the stack.
+    fn block(&mut self) -> Result<(), CompilerError> {
+        self.begin_scope();
+
+        let mut has_value = false;
+        while !self.check(TokenKind::RightCurly)? {
+            has_value = self.declaration()?;
+        }
+        self.consume(TokenKind::RightCurly)?;
+
+        if !has_value {
+            self.emit_op_code(OpCode::Nil);
+        }
+        self.end_scope_keeping_value();
+
+        Ok(())
+    }
+
+    fn expression_statement(&mut self) -> Result<bool, CompilerError> {
+        self.expression()?;
+
+        if self.at_block_end()? {
+            return Ok(true);
+        }
+
+        self.consume(TokenKind::Semicolon)?;
+        self.emit_op_code(OpCode::Pop);
+
+        Ok(false)
+    }
+
+    fn begin_scope(&mut self) {
+        self.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.scope_depth -= 1;
+
+        while self
+            .locals
+            .last()
+            .is_some_and(|local| local.depth > Some(self.scope_depth))
+        {
+            self.locals.pop();
+            self.emit_op_code(OpCode::Pop);
+        }
+    }
+
+    /// Pops the scope's locals from underneath the block's value by moving
+    /// the value into the first local's slot and popping everything above it.
+    fn end_scope_keeping_value(&mut self) {
+        self.scope_depth -= 1;
+
+        let scope_start = self
+            .locals
+            .iter()
+            .rposition(|local| local.depth <= Some(self.scope_depth))
+            .map_or(0, |index| index + 1);
+
+        let Some(first) = self.locals.get(scope_start) else {
+            return;
+        };
+
+        let slot = first.slot;
+        let popped = self.locals.len() - scope_start;
+        self.locals.truncate(scope_start);
+
+        self.emit_op_code_operand(OpCode::SetLocal, slot);
+        for _ in 0..popped {
+            self.emit_op_code(OpCode::Pop);
+        }
+    }
+
+    fn declare_local(&mut self, name: &'source str, constant: bool) -> Result<(), CompilerError> {
+        for local in self.locals.iter().rev() {
+            if local.depth < Some(self.scope_depth) {
+                break;
+            }
+            // Hidden locals are named "", which no identifier can refer to.
+            if local.name == name && !name.is_empty() {
+                return Err(CompilerError::AlreadyDeclared);
+            }
+        }
+
+        if self.stack_depth >= LOCALS_MAX {
+            return Err(CompilerError::TooManyLocals);
+        }
+
+        self.locals.push(Local {
+            name,
+            slot: self.stack_depth as u8,
+            depth: None,
+            constant,
+        });
+
+        Ok(())
+    }
+
+    fn mark_initialized(&mut self) {
+        if let Some(local) = self.locals.last_mut() {
+            local.depth = Some(self.scope_depth);
+        }
+    }
+
+    /// Returns the index into `locals` of the innermost local named `name`.
+    fn resolve_local(&self, name: &str) -> Result<Option<usize>, CompilerError> {
+        match self.locals.iter().rposition(|local| local.name == name) {
+            Some(index) if self.locals[index].depth.is_none() => {
+                Err(CompilerError::ReadInOwnInitializer)
+            }
+            index => Ok(index),
+        }
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) -> Result<(), CompilerError> {
-        let lhs = self.scanner.next()?;
+        let lhs = self.advance()?;
+        let can_assign = precedence <= Precedence::Assignment;
 
         ParseRule::from_token(&lhs)
             .prefix
-            .ok_or(CompilerError::UnexpectedEOF)?(self)?;
+            .ok_or(CompilerError::ExpectedExpression)?(self, can_assign)?;
 
         loop {
-            let infix_rule = ParseRule::from_token(&self.peek()?).infix.unwrap();
-            infix_rule(self)?;
+            let operator = match self.scanner.peek() {
+                Err(ScannerError::Eof) => break,
+                operator => operator?,
+            };
+
+            if precedence > ParseRule::from_token(&operator).precedence {
+                break;
+            }
+
+            self.advance()?;
+            ParseRule::from_token(&operator)
+                .infix
+                .ok_or(CompilerError::ExpectedExpression)?(self, can_assign)?;
+        }
+
+        if can_assign && self.peek_kind()?.is_some_and(is_assignment) {
+            return Err(CompilerError::InvalidAssignmentTarget);
         }
 
         Ok(())
@@ -118,25 +992,109 @@ impl<'source> Compiler<'source> {
         self.parse_precedence(Precedence::Assignment)
     }
 
-    fn grouping(&mut self) -> Result<(), CompilerError> {
-        self.expression()?;
-        self.consume(TokenKind::RightParen);
+    /// A parenthesized expression, or a tuple if it contains a comma. `()` is
+    /// the empty tuple and `(a,)` a tuple with one element.
+    fn grouping(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        let mut len = 0usize;
+        let mut is_tuple = true;
+        while !self.check(TokenKind::RightParen)? {
+            self.expression()?;
+            len += 1;
+            is_tuple = self.matches(TokenKind::Comma)?;
+            if !is_tuple {
+                break;
+            }
+        }
+        self.consume(TokenKind::RightParen)?;
+
+        if is_tuple || len > 1 {
+            let len = u8::try_from(len).map_err(|_| CompilerError::TooManyElements)?;
+            self.emit_op_code_operand(OpCode::BuildTuple, len);
+        }
+
+        Ok(())
+    }
+
+    /// Named arguments, `name: value`, come after the positional ones.
+    fn call(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        let mut argc = 0usize;
+        let mut names = Vec::new();
+
+        while !self.check(TokenKind::RightParen)? {
+            let named = self.check(TokenKind::Identifier)?
+                && self
+                    .scanner
+                    .peek_nth(1)
+                    .is_ok_and(|token| token.kind == TokenKind::Colon);
+            if named {
+                let name = self.advance()?.lexeme;
+                self.advance()?;
+                names.push(Value::from(name));
+            } else if !names.is_empty() {
+                return Err(CompilerError::PositionalAfterNamed);
+            }
+
+            self.expression()?;
+            argc += 1;
+            if !self.matches(TokenKind::Comma)? {
+                break;
+            }
+        }
+        self.consume(TokenKind::RightParen)?;
+
+        let argc = u8::try_from(argc).map_err(|_| CompilerError::TooManyElements)?;
+        if names.is_empty() {
+            self.emit_op_code_operand(OpCode::Call, argc);
+        } else {
+            let names = self.make_constant(Value::new_tuple(names));
+            self.emit_constant_instruction(OpCode::CallNamed, names, &[argc])?;
+        }
+
+        Ok(())
+    }
+
+    /// `result?` unwraps an `Ok`, and returns an `Err` from the current
+    /// function as it is.
+    fn propagate(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        self.emit_op_code(OpCode::Propagate);
 
         Ok(())
     }
 
-    fn binary(&mut self) -> Result<(), CompilerError> {
-        let operator_kind = self.scanner.peek()?.kind;
+    fn binary(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        let operator_kind = self.previous().kind;
 
         let rule = ParseRule::from_kind(operator_kind);
 
-        self.parse_precedence(rule.precedence.next())?;
+        if operator_kind == TokenKind::StarStar {
+            // Right-associative: `a ** b ** c` is `a ** (b ** c)`.
+            self.parse_precedence(rule.precedence)?;
+        } else {
+            self.parse_precedence(rule.precedence.next())?;
+        }
 
         match operator_kind {
             TokenKind::Plus => self.emit_op_code(OpCode::Add),
             TokenKind::Minus => self.emit_op_code(OpCode::Subtract),
             TokenKind::Star => self.emit_op_code(OpCode::Multiply),
             TokenKind::Slash => self.emit_op_code(OpCode::Divide),
+            TokenKind::TildeSlash => self.emit_op_code(OpCode::FloorDivide),
+            TokenKind::Percent => self.emit_op_code(OpCode::Modulo),
+            TokenKind::StarStar => self.emit_op_code(OpCode::Power),
+            TokenKind::Pipe => self.emit_op_code(OpCode::BitOr),
+            TokenKind::Caret => self.emit_op_code(OpCode::BitXor),
+            TokenKind::Ampersand => self.emit_op_code(OpCode::BitAnd),
+            TokenKind::LessLess => self.emit_op_code(OpCode::ShiftLeft),
+            TokenKind::GreaterGreater => self.emit_op_code(OpCode::ShiftRight),
+            TokenKind::EqualEqual => self.emit_op_code(OpCode::Equal),
+            TokenKind::BangEqual => {
+                self.emit_op_code(OpCode::Equal);
+                self.emit_op_code(OpCode::Not);
+            }
+            TokenKind::Greater => self.emit_op_code(OpCode::Greater),
+            TokenKind::GreaterEqual => self.emit_op_code(OpCode::GreaterEqual),
+            TokenKind::Less => self.emit_op_code(OpCode::Less),
+            TokenKind::LessEqual => self.emit_op_code(OpCode::LessEqual),
 
             _ => Err(CompilerError::InvalidOperator)?,
         }
@@ -144,64 +1102,817 @@ impl<'source> Compiler<'source> {
         Ok(())
     }
 
-    fn unary(&mut self) -> Result<(), CompilerError> {
-        let operator_kind = self.peek()?.kind;
+    fn unary(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        let operator_kind = self.previous().kind;
 
         self.parse_precedence(Precedence::Unary)?;
 
         match operator_kind {
             TokenKind::Minus => self.emit_op_code(OpCode::Negate),
-            _ => panic!("invalid unary"),
+            TokenKind::Tilde => self.emit_op_code(OpCode::BitNot),
+            TokenKind::Bang => self.emit_op_code(OpCode::Not),
+            _ => Err(CompilerError::InvalidOperator)?,
         };
 
         Ok(())
     }
 
-    fn number(&mut self) -> Result<(), CompilerError> {
-        dbg!(self.peek());
-        let value = self.peek()?.lexeme.parse().unwrap();
-        self.emit_constant(value);
+    fn and(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
 
-        Ok(())
-    }
+        self.emit_op_code(OpCode::Pop);
+        self.parse_precedence(Precedence::And)?;
 
-    fn error_at_current(&self, err: ScannerError) {
-        todo!()
+        self.patch_jump(end_jump)
     }
 
-    fn emit_op_code(&mut self, op: OpCode) {
-        let line = self.peek().unwrap().line;
-        self.compiling_chunk.write_op_code(op, line)
-    }
+    fn or(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
 
-    fn emit_operand(&mut self, operand: u8) {
-        let line = self.peek().unwrap().line;
+        self.patch_jump(else_jump)?;
+        self.emit_op_code(OpCode::Pop);
+        self.parse_precedence(Precedence::Or)?;
 
-        self.compiling_chunk.write_operand(operand, line)
+        self.patch_jump(end_jump)
     }
 
-    fn emit_op_code_operand(&mut self, op: OpCode, operand: u8) {
-        self.emit_op_code(op);
-        self.emit_operand(operand);
+    fn block_expression(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        self.block()
     }
 
-    fn emit_constant(&mut self, value: Value) {
-        let const_index = self.make_constant(value);
-        self.emit_op_code_operand(OpCode::Constant, const_index);
-    }
+    /// `if` always produces a value, `nil` when there is no `else` branch.
+    fn if_expression(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        self.expression()?;
 
-    fn make_constant(&mut self, value: f64) -> u8 {
-        self.compiling_chunk.add_constant(value)
-    }
-}
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op_code(OpCode::Pop);
+        self.consume(TokenKind::LeftCurly)?;
+        self.block()?;
 
-#[derive(Clone, Copy, Debug)]
+        // The then branch's value stands in for the condition the else branch
+        // starts by popping, so the tracked stack depth stays accurate.
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump)?;
+        self.emit_op_code(OpCode::Pop);
+
+        if self.matches(TokenKind::Else)? {
+            if self.matches(TokenKind::If)? {
+                self.if_expression(false)?;
+            } else {
+                self.consume(TokenKind::LeftCurly)?;
+                self.block()?;
+            }
+        } else {
+            self.emit_op_code(OpCode::Nil);
+        }
+
+        self.patch_jump(else_jump)
+    }
+
+    /// `try { ... } catch error { ... } finally { ... }` where either the
+    /// `catch` or the `finally` may be left out, and `catch` takes any
+    /// pattern. Its value is the `try` block's, or the `catch` block's if
+    /// something was thrown. `finally` always runs last, even on `return`,
+    /// and its value is dropped.
+    fn try_expression(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        let base = self.stack_depth;
+
+        // Whether there are `catch` and `finally` clauses is only known
+        // after the block, so their handlers are patched out if not.
+        let finally_handler = self.emit_jump(OpCode::PushFinally);
+        let catch_handler = self.emit_jump(OpCode::PushHandler);
+
+        self.consume(TokenKind::LeftCurly)?;
+        self.block()?;
+
+        let has_catch = self.matches(TokenKind::Catch)?;
+        if has_catch {
+            self.emit_op_code(OpCode::PopHandler);
+            let skip_catch = self.emit_jump(OpCode::Jump);
+
+            // The VM drops everything above `base` and pushes the thrown value.
+            self.patch_jump(catch_handler)?;
+            self.stack_depth = base;
+            self.begin_scope();
+            let pattern = match self.check(TokenKind::LeftCurly)? {
+                true => Pattern::Wildcard,
+                false => self.pattern()?,
+            };
+            let thrown = self.stack_depth as u8;
+            match pattern {
+                Pattern::Binding(name) => self.declare_local(name, false)?,
+                _ => self.declare_local("", false)?,
+            }
+            self.stack_depth += 1;
+            self.mark_initialized();
+            if !matches!(pattern, Pattern::Binding(_)) {
+                self.destructure(&pattern, thrown, false)?;
+            }
+
+            self.consume(TokenKind::LeftCurly)?;
+            self.block()?;
+            self.end_scope_keeping_value();
+
+            self.patch_jump(skip_catch)?;
+        } else {
+            self.remove_handler(catch_handler);
+        }
+
+        if self.matches(TokenKind::Finally)? {
+            // The `finally` block runs with the value and how the `try` was
+            // left on the stack, for `END_FINALLY` to carry on with.
+            self.emit_op_code(OpCode::PopHandler);
+            self.emit_constant(Value::Integer(0))?;
+            self.patch_jump(finally_handler)?;
+
+            self.consume(TokenKind::LeftCurly)?;
+            self.block()?;
+            self.emit_op_code(OpCode::Pop);
+            self.emit_op_code(OpCode::EndFinally);
+        } else if has_catch {
+            self.remove_handler(finally_handler);
+        } else {
+            return Err(CompilerError::ExpectedToken(TokenKind::Catch));
+        }
+
+        Ok(())
+    }
+
+    /// Turns the handler push whose operand is at `offset` into a jump to
+    /// the next instruction.
+    fn remove_handler(&mut self, offset: usize) {
+        let code = &mut self.compiling_chunk.code;
+        code[offset - 1] = OpCode::Jump.into();
+        code[offset..offset + 2].fill(0);
+    }
+
+    fn literal(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        let value =
+            literal_value(&self.previous().literal).ok_or(CompilerError::ExpectedExpression)?;
+        self.emit_constant(value)?;
+
+        Ok(())
+    }
+
+    fn keyword_literal(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        match self.previous().kind {
+            TokenKind::True => self.emit_op_code(OpCode::True),
+            TokenKind::False => self.emit_op_code(OpCode::False),
+            TokenKind::Nil => self.emit_op_code(OpCode::Nil),
+            _ => Err(CompilerError::ExpectedExpression)?,
+        }
+
+        Ok(())
+    }
+
+    fn variable(&mut self, can_assign: bool) -> Result<(), CompilerError> {
+        let name = self.previous().lexeme;
+
+        // Locals shadow enums, globals can't share their names.
+        if self.resolve_local(name)?.is_none() {
+            if self.enums.contains_key(name) {
+                let variant = self.resolve_variant(name)?;
+                return self.enum_variant(variant);
+            }
+            if let Some(variant) = self.prelude_variant(name) {
+                return self.enum_variant(variant);
+            }
+        }
+
+        self.named_variable(name, can_assign)
+    }
+
+    /// Unit variants are constants, payload variants are built from their
+    /// arguments at runtime.
+    fn enum_variant(&mut self, variant: Rc<Variant>) -> Result<(), CompilerError> {
+        let template = Value::new_variant(variant.clone(), Vec::new());
+
+        if variant.arity == 0 {
+            self.emit_constant(template)?;
+            return Ok(());
+        }
+
+        self.consume(TokenKind::LeftParen)?;
+        let mut count = 0usize;
+        while !self.check(TokenKind::RightParen)? {
+            self.expression()?;
+            count += 1;
+            if !self.matches(TokenKind::Comma)? {
+                break;
+            }
+        }
+        self.consume(TokenKind::RightParen)?;
+
+        if count != variant.arity as usize {
+            return Err(CompilerError::WrongPayloadCount);
+        }
+
+        let template = self.make_constant(template);
+        self.emit_constant_instruction(OpCode::BuildVariant, template, &[variant.arity])?;
+
+        Ok(())
+    }
+
+    fn named_variable(
+        &mut self,
+        name: &'source str,
+        can_assign: bool,
+    ) -> Result<(), CompilerError> {
+        let (get_op, set_op, operand, constant) = match self.resolve_local(name)? {
+            Some(index) => (
+                OpCode::GetLocal,
+                OpCode::SetLocal,
+                self.locals[index].slot.into(),
+                self.locals[index].constant,
+            ),
+            None if self
+                .enclosing_locals
+                .iter()
+                .any(|locals| locals.contains(&name)) =>
+            {
+                return Err(CompilerError::EnclosingLocal)
+            }
+            None => (
+                OpCode::GetGlobal,
+                OpCode::SetGlobal,
+                self.identifier_constant(name),
+                self.constant_globals.contains(name),
+            ),
+        };
+
+        let Some(assignment) = self.assignment_operator(can_assign)? else {
+            return self.emit_variable_op(get_op, operand);
+        };
+
+        if constant {
+            return Err(CompilerError::AssignToConstant);
+        }
+
+        // Compound assignments compile to a get, the operator and a set.
+        let operator = compound_operator(assignment);
+        if operator.is_some() {
+            self.emit_variable_op(get_op, operand)?;
+        }
+        self.expression()?;
+        if let Some(operator) = operator {
+            self.emit_op_code(operator);
+        }
+        self.emit_variable_op(set_op, operand)
+    }
+
+    /// Gets or sets a local by its slot, or a global by its name's constant.
+    fn emit_variable_op(&mut self, op: OpCode, operand: usize) -> Result<(), CompilerError> {
+        if op.has_constant_operand() {
+            return self.emit_constant_instruction(op, operand, &[]);
+        }
+        self.emit_op_code_operand(op, operand as u8);
+        Ok(())
+    }
+
+    /// Consumes an assignment operator if one follows and assignment is
+    /// allowed here.
+    fn assignment_operator(
+        &mut self,
+        can_assign: bool,
+    ) -> Result<Option<TokenKind>, CompilerError> {
+        match self.peek_kind()? {
+            Some(kind) if can_assign && is_assignment(kind) => {
+                self.advance()?;
+                Ok(Some(kind))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn list(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        let mut len = 0usize;
+        while !self.check(TokenKind::RightBrace)? {
+            self.expression()?;
+            len += 1;
+            if !self.matches(TokenKind::Comma)? {
+                break;
+            }
+        }
+        self.consume(TokenKind::RightBrace)?;
+
+        let len = u8::try_from(len).map_err(|_| CompilerError::TooManyElements)?;
+        self.emit_op_code_operand(OpCode::BuildList, len);
+
+        Ok(())
+    }
+
+    fn index(&mut self, can_assign: bool) -> Result<(), CompilerError> {
+        self.expression()?;
+        self.consume(TokenKind::RightBrace)?;
+
+        match self.assignment_operator(can_assign)? {
+            None => self.emit_op_code(OpCode::GetIndex),
+            Some(TokenKind::Equal) => {
+                self.expression()?;
+                self.emit_op_code(OpCode::SetIndex);
+            }
+            Some(assignment) => {
+                self.emit_op_code(OpCode::DuplicatePair);
+                self.emit_op_code(OpCode::GetIndex);
+                self.expression()?;
+                if let Some(operator) = compound_operator(assignment) {
+                    self.emit_op_code(operator);
+                }
+                self.emit_op_code(OpCode::SetIndex);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Arms are tried in order. Each arm first runs all of its pattern's
+    /// tests, then binds its variables and checks its guard, so a failed test
+    /// never has bindings to clean up. Without a matching arm the value is
+    /// `nil`.
+    fn match_expression(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        self.begin_scope();
+
+        // The subject lives in a hidden local so that every arm can inspect it.
+        self.declare_local("", true)?;
+        let subject = self.stack_depth as u8;
+        self.expression()?;
+        self.mark_initialized();
+
+        self.consume(TokenKind::LeftCurly)?;
+
+        let line = self.current_line();
+        let mut end_jumps = Vec::new();
+        let mut arms = Vec::new();
+        while !self.check(TokenKind::RightCurly)? {
+            let (end_jump, pattern, guarded) = self.match_arm(subject)?;
+            end_jumps.push(end_jump);
+            arms.push((pattern, guarded));
+
+            if self.check(TokenKind::RightCurly)? {
+                break;
+            }
+            if self.previous().kind == TokenKind::RightCurly {
+                self.matches(TokenKind::Comma)?;
+            } else {
+                self.consume(TokenKind::Comma)?;
+            }
+        }
+        self.consume(TokenKind::RightCurly)?;
+        self.check_exhaustive(&arms, line);
+
+        self.emit_op_code(OpCode::Nil);
+        for jump in end_jumps {
+            self.patch_jump(jump)?;
+        }
+
+        self.end_scope_keeping_value();
+
+        Ok(())
+    }
+
+    /// Warns about a `match` on an enum that misses some of its variants. A
+    /// variant counts as covered by an arm without a guard whose fields are
+    /// all irrefutable.
+    fn check_exhaustive(&mut self, arms: &[(Pattern<'source>, bool)], line: usize) {
+        let unguarded = || arms.iter().filter(|(_, guarded)| !guarded);
+        if unguarded().any(|(pattern, _)| pattern.is_irrefutable()) {
+            return;
+        }
+
+        let Some(enum_name) = arms.iter().find_map(|(pattern, _)| match pattern {
+            Pattern::Variant { variant, .. } => Some(variant.enum_name.clone()),
+            _ => None,
+        }) else {
+            return;
+        };
+
+        let missing: Vec<_> = self.enums[&*enum_name]
+            .iter()
+            .filter(|variant| {
+                !unguarded().any(|(pattern, _)| match pattern {
+                    Pattern::Variant {
+                        variant: covered,
+                        fields,
+                    } => covered == *variant && fields.iter().all(Pattern::is_irrefutable),
+                    _ => false,
+                })
+            })
+            .map(|variant| format!("{}.{}", variant.enum_name, variant.name))
+            .collect();
+
+        if !missing.is_empty() {
+            self.warnings
+                .push(CompilerWarning::NonExhaustiveMatch { line, missing });
+        }
+    }
+
+    /// Compiles an arm and returns the jump its body takes to the end of the
+    /// `match`, along with its pattern and whether it has a guard.
+    fn match_arm(&mut self, subject: u8) -> Result<(usize, Pattern<'source>, bool), CompilerError> {
+        let pattern = self.pattern()?;
+        let arm_depth = self.stack_depth;
+
+        let mut test_jumps = Vec::new();
+        self.emit_pattern_tests(&pattern, subject, &mut Vec::new(), &mut test_jumps)?;
+
+        self.begin_scope();
+
+        let mut bindings = Vec::new();
+        collect_bindings(&pattern, &mut Vec::new(), &mut bindings);
+        for (name, path) in &bindings {
+            self.declare_local(name, false)?;
+            self.emit_path(subject, path)?;
+            self.mark_initialized();
+        }
+
+        let guard_jump = if self.matches(TokenKind::If)? {
+            self.expression()?;
+            let jump = self.emit_jump(OpCode::JumpIfFalse);
+            self.emit_op_code(OpCode::Pop);
+            Some(jump)
+        } else {
+            None
+        };
+
+        self.consume(TokenKind::FatArrow)?;
+        self.expression()?;
+        self.end_scope_keeping_value();
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        // A failed guard leaves its `false` above the bindings. Move it down
+        // so the stack looks like it does after a failed test.
+        let can_fail = guard_jump.is_some() || !test_jumps.is_empty();
+        if let Some(guard_jump) = guard_jump {
+            self.patch_jump(guard_jump)?;
+            self.stack_depth = arm_depth + bindings.len() + 1;
+
+            if !bindings.is_empty() {
+                self.emit_op_code_operand(OpCode::SetLocal, arm_depth as u8);
+                for _ in 0..bindings.len() {
+                    self.emit_op_code(OpCode::Pop);
+                }
+            }
+        }
+
+        // Every failed test jumps here with its `false` on the stack.
+        if can_fail {
+            for jump in test_jumps {
+                self.patch_jump(jump)?;
+            }
+            self.stack_depth = arm_depth + 1;
+            self.emit_op_code(OpCode::Pop);
+        }
+
+        self.stack_depth = arm_depth;
+
+        Ok((end_jump, pattern, guard_jump.is_some()))
+    }
+
+    fn pattern(&mut self) -> Result<Pattern<'source>, CompilerError> {
+        let token = self.advance()?;
+
+        match token.kind {
+            TokenKind::Identifier if token.lexeme == "_" => Ok(Pattern::Wildcard),
+            TokenKind::Identifier if self.check(TokenKind::Dot)? => {
+                let variant = self.resolve_variant(token.lexeme)?;
+                self.variant_pattern(variant)
+            }
+            TokenKind::Identifier => match self.prelude_variant(token.lexeme) {
+                Some(variant) => self.variant_pattern(variant),
+                None => Ok(Pattern::Binding(token.lexeme)),
+            },
+            TokenKind::LeftBrace => self.sequence_pattern(TokenKind::RightBrace),
+            TokenKind::LeftParen => self.sequence_pattern(TokenKind::RightParen),
+            _ => {
+                let value = self.pattern_literal(&token)?;
+
+                if self.matches(TokenKind::DotDot)? {
+                    let upper = self.advance()?;
+                    let upper = self.pattern_literal(&upper)?;
+                    Ok(Pattern::Range(value, upper))
+                } else {
+                    Ok(Pattern::Literal(value))
+                }
+            }
+        }
+    }
+
+    fn pattern_literal(&mut self, token: &Token<'source>) -> Result<Value, CompilerError> {
+        match token.kind {
+            TokenKind::True => Ok(Value::Bool(true)),
+            TokenKind::False => Ok(Value::Bool(false)),
+            TokenKind::Nil => Ok(Value::Nil),
+            TokenKind::Literal => {
+                literal_value(&token.literal).ok_or(CompilerError::ExpectedPattern)
+            }
+            TokenKind::Minus => {
+                let number = self.consume(TokenKind::Literal)?;
+                literal_value(&number.literal)
+                    .and_then(|value| value.negate())
+                    .ok_or(CompilerError::ExpectedPattern)
+            }
+            _ => Err(CompilerError::ExpectedPattern),
+        }
+    }
+
+    fn variant_pattern(&mut self, variant: Rc<Variant>) -> Result<Pattern<'source>, CompilerError> {
+        let mut fields = Vec::new();
+        if self.matches(TokenKind::LeftParen)? {
+            while !self.check(TokenKind::RightParen)? {
+                fields.push(self.pattern()?);
+                if !self.matches(TokenKind::Comma)? {
+                    break;
+                }
+            }
+            self.consume(TokenKind::RightParen)?;
+
+            if fields.len() != variant.arity as usize {
+                return Err(CompilerError::WrongPayloadCount);
+            }
+        }
+
+        Ok(Pattern::Variant { variant, fields })
+    }
+
+    /// A list pattern, or a tuple pattern when `closing` is `)`. Like in
+    /// expressions, parentheses around a single pattern without a comma only
+    /// group it.
+    fn sequence_pattern(&mut self, closing: TokenKind) -> Result<Pattern<'source>, CompilerError> {
+        let tuple = closing == TokenKind::RightParen;
+        let mut elements = Vec::new();
+        let mut rest = None;
+        let mut trailing_comma = true;
+
+        while !self.check(closing)? {
+            if self.matches(TokenKind::DotDot)? {
+                if rest.is_some() {
+                    return Err(CompilerError::MultipleRestPatterns);
+                }
+                let name = match self.peek()? {
+                    token if token.kind == TokenKind::Identifier => {
+                        self.advance()?;
+                        Some(token.lexeme).filter(|&name| name != "_")
+                    }
+                    _ => None,
+                };
+                rest = Some((elements.len(), name));
+            } else {
+                elements.push(self.pattern()?);
+            }
+
+            trailing_comma = self.matches(TokenKind::Comma)?;
+            if !trailing_comma {
+                break;
+            }
+        }
+        self.consume(closing)?;
+
+        if elements.len() > u8::MAX as usize {
+            return Err(CompilerError::TooManyElements);
+        }
+
+        if tuple && !trailing_comma && rest.is_none() && elements.len() == 1 {
+            return Ok(elements.pop().expect("there is one element"));
+        }
+
+        Ok(Pattern::Sequence {
+            tuple,
+            elements,
+            rest,
+        })
+    }
+
+    /// Emits the checks for everything except bindings. Each check jumps to
+    /// a failure label with a `false` on the stack.
+    fn emit_pattern_tests(
+        &mut self,
+        pattern: &Pattern<'source>,
+        subject: u8,
+        path: &mut Vec<PathStep>,
+        fail_jumps: &mut Vec<usize>,
+    ) -> Result<(), CompilerError> {
+        match pattern {
+            Pattern::Wildcard | Pattern::Binding(_) => return Ok(()),
+            Pattern::Literal(value) => {
+                self.emit_path(subject, path)?;
+                self.emit_constant(value.clone())?;
+                self.emit_op_code(OpCode::Equal);
+            }
+            Pattern::Range(lower, upper) => {
+                self.emit_path(subject, path)?;
+                self.emit_constant(lower.clone())?;
+                self.emit_constant(upper.clone())?;
+                self.emit_op_code(OpCode::MatchRange);
+            }
+            Pattern::Sequence {
+                tuple,
+                elements,
+                rest,
+            } => {
+                self.emit_path(subject, path)?;
+                self.emit_instruction(
+                    if *tuple {
+                        OpCode::MatchTuple
+                    } else {
+                        OpCode::MatchList
+                    },
+                    &[elements.len() as u8, rest.is_some() as u8],
+                );
+                fail_jumps.push(self.emit_jump(OpCode::JumpIfFalse));
+                self.emit_op_code(OpCode::Pop);
+
+                for (i, element) in elements.iter().enumerate() {
+                    path.push(PathStep::Index(element_index(i, elements.len(), rest)));
+                    self.emit_pattern_tests(element, subject, path, fail_jumps)?;
+                    path.pop();
+                }
+                return Ok(());
+            }
+            Pattern::Variant { variant, fields } => {
+                self.emit_path(subject, path)?;
+                let template = self.make_constant(Value::new_variant(variant.clone(), Vec::new()));
+                self.emit_constant_instruction(OpCode::MatchVariant, template, &[])?;
+                fail_jumps.push(self.emit_jump(OpCode::JumpIfFalse));
+                self.emit_op_code(OpCode::Pop);
+
+                for (i, field) in fields.iter().enumerate() {
+                    path.push(PathStep::Payload(i as u8));
+                    self.emit_pattern_tests(field, subject, path, fail_jumps)?;
+                    path.pop();
+                }
+                return Ok(());
+            }
+        }
+
+        fail_jumps.push(self.emit_jump(OpCode::JumpIfFalse));
+        self.emit_op_code(OpCode::Pop);
+
+        Ok(())
+    }
+
+    /// Pushes the part of the subject that `path` leads to.
+    fn emit_path(&mut self, subject: u8, path: &[PathStep]) -> Result<(), CompilerError> {
+        self.emit_op_code_operand(OpCode::GetLocal, subject);
+        for step in path {
+            match *step {
+                PathStep::Index(index) => {
+                    self.emit_constant(Value::Integer(index))?;
+                    self.emit_op_code(OpCode::GetIndex);
+                }
+                PathStep::Slice(front, back) => {
+                    self.emit_instruction(OpCode::Slice, &[front, back]);
+                }
+                PathStep::Payload(index) => {
+                    self.emit_op_code_operand(OpCode::GetPayload, index);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn emit_instruction(&mut self, op: OpCode, operands: &[u8]) {
+        debug_assert_eq!(operands.len(), op.num_operands());
+
+        let (line, column) = (self.current_line(), self.current_column());
+        self.stack_depth = self
+            .stack_depth
+            .checked_add_signed(op.stack_effect(operands))
+            .expect("emitted code pops more values than it pushed");
+
+        self.compiling_chunk.write_op_code(op, line, column);
+        for &operand in operands {
+            self.compiling_chunk.write_operand(operand, line, column);
+        }
+    }
+
+    fn emit_op_code(&mut self, op: OpCode) {
+        self.emit_instruction(op, &[]);
+    }
+
+    fn emit_op_code_operand(&mut self, op: OpCode, operand: u8) {
+        self.emit_instruction(op, &[operand]);
+    }
+
+    /// Emits a jump with a placeholder target and returns the placeholder's
+    /// offset for `patch_jump`.
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_instruction(op, &[u8::MAX, u8::MAX]);
+        self.compiling_chunk.code.len() - 2
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) -> Result<(), CompilerError> {
+        let offset = self.compiling_chunk.code.len() + 3 - loop_start;
+        let offset = u16::try_from(offset).map_err(|_| CompilerError::JumpTooLarge)?;
+
+        self.emit_instruction(OpCode::Loop, &offset.to_be_bytes());
+
+        Ok(())
+    }
+
+    /// Points the jump at `offset` to the next instruction to be emitted.
+    fn patch_jump(&mut self, offset: usize) -> Result<(), CompilerError> {
+        let jump = self.compiling_chunk.code.len() - offset - 2;
+        let jump = u16::try_from(jump).map_err(|_| CompilerError::JumpTooLarge)?;
+
+        self.compiling_chunk.code[offset..offset + 2].copy_from_slice(&jump.to_be_bytes());
+
+        Ok(())
+    }
+
+    fn emit_constant(&mut self, value: Value) -> Result<(), CompilerError> {
+        let const_index = self.make_constant(value);
+        self.emit_constant_instruction(OpCode::Constant, const_index, &[])
+    }
+
+    /// Emits `op` with the constant at `const_index` and the rest of its
+    /// `operands`, switching to the `_LONG` form once the index doesn't fit
+    /// in a byte.
+    fn emit_constant_instruction(
+        &mut self,
+        op: OpCode,
+        const_index: usize,
+        operands: &[u8],
+    ) -> Result<(), CompilerError> {
+        let (op, mut bytes) = match u8::try_from(const_index) {
+            Ok(const_index) => (op, vec![const_index]),
+            Err(_) if const_index < 1 << 24 => {
+                let [_, high, middle, low] = (const_index as u32).to_be_bytes();
+                let long = op.long_form().expect("the instruction takes a constant");
+                (long, vec![high, middle, low])
+            }
+            Err(_) => return Err(CompilerError::TooManyConstants),
+        };
+        bytes.extend_from_slice(operands);
+        self.emit_instruction(op, &bytes);
+
+        Ok(())
+    }
+
+    fn make_constant(&mut self, value: Value) -> usize {
+        self.compiling_chunk.add_constant(value)
+    }
+
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        self.make_constant(Value::String(name.into()))
+    }
+
+    fn current_line(&self) -> usize {
+        self.previous.as_ref().map_or(0, |token| token.line)
+    }
+
+    fn current_column(&self) -> usize {
+        self.previous.as_ref().map_or(0, |token| token.column)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum CompilerError {
     UnexpectedEOF,
     InvalidOperator,
+    ExpectedExpression,
+    ExpectedToken(TokenKind),
+    InvalidAssignmentTarget,
+    AssignToConstant,
+    AlreadyDeclared,
+    MissingInitializer,
+    ReadInOwnInitializer,
+    TooManyLocals,
+    TooManyConstants,
+    JumpTooLarge,
+    TooManyElements,
+    ExpectedPattern,
+    MultipleRestPatterns,
+    UnknownEnum,
+    UnknownVariant,
+    WrongPayloadCount,
+    PositionalAfterNamed,
+    EnclosingLocal,
     ScannerError(ScannerError),
 }
 
+/// Problems that don't stop the program from compiling.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompilerWarning {
+    /// A `match` on an enum has no arm for some of its variants, which then
+    /// evaluate to `nil`.
+    NonExhaustiveMatch { line: usize, missing: Vec<String> },
+}
+
+impl Display for CompilerWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompilerWarning::NonExhaustiveMatch { line, missing } => write!(
+                f,
+                "[line {line}] match doesn't cover {}",
+                missing.join(", ")
+            ),
+        }
+    }
+}
+
 impl From<ScannerError> for CompilerError {
     fn from(value: ScannerError) -> Self {
         match value {
@@ -211,17 +1922,1084 @@ impl From<ScannerError> for CompilerError {
     }
 }
 
+#[cfg(test)]
 mod test {
-    use crate::{compiler::scanner::Scanner, virtual_machine::chunk::disassemble_operation};
+    use crate::{
+        compiler::{
+            compiler::{CompilerError, CompilerWarning},
+            token::TokenKind,
+        },
+        virtual_machine::{
+            error::{ErrorKind, RuntimeError, TraceFrame},
+            heap::GcMode,
+            op_code::OpCode,
+            value::Value,
+            vm::{Config, RunState, VM},
+        },
+    };
+
+    fn run(source: &str) -> Result<Value, RuntimeError> {
+        let chunk = super::compile(source).unwrap();
+        chunk.verify().unwrap();
+        VM::new(chunk).run()
+    }
+
+    fn assert_value(source: &str, expected: impl Into<Value>) {
+        assert_eq!(run(source), Ok(expected.into()), "{source}");
+    }
 
     #[test]
     fn the_very_basics() {
-        let mut compiler = super::Compiler::new(Scanner::new("1 + 2 * 3"));
+        assert_value("1 + 2 * 3", 7);
+        assert_value("(1 + 2) * 3", 9);
+        assert_value("8 - 4 - 2", 2);
+        assert_value("-2 * -(3 - 1.5)", 3.0);
+        assert_value("0x10 + 0b1 * 1e1", 26.0);
+    }
+
+    #[test]
+    fn arithmetic_operators() {
+        assert_value("7 / 2", 3.5);
+        assert_value("7 % 3", 1);
+        assert_value("-7 % 3", 2);
+        assert_value("7 % -3", -2);
+        assert_value("7.5 % 2", 1.5);
+        assert_value("7 ~/ 2", 3);
+        assert_value("-7 ~/ 2", -4);
+        assert_value("7.5 ~/ 2", 3.0);
+        assert_value("2 ** 10", 1024);
+        assert_value("2 ** 3 ** 2", 512);
+        assert_value("-2 ** 2", -4);
+        assert_value("2 ** -1", 0.5);
+        assert_value("2 * 3 ** 2", 18);
+        assert_value("\"lof\" + \"-lang\"", "lof-lang");
+
+        assert!(run("1 ~/ 0").is_err());
+        assert!(run("1 % 0").is_err());
+        assert!(run("nil + 1").is_err());
+        assert!(run("2 ** 64").is_err());
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        assert_value("12 & 10", 8);
+        assert_value("12 | 10", 14);
+        assert_value("12 ^ 10", 6);
+        assert_value("~5", -6);
+        assert_value("1 << 4", 16);
+        assert_value("-16 >> 2", -4);
+        assert_value("1 | 2 ^ 6 & 12", 7);
+        assert_value("1 << 2 + 1", 8);
+        assert_value("6 & 3 == 2", true);
+
+        assert_value(
+            "try { 1.0 & 1 } catch e { e }",
+            "bitwise operands must be integers, found float",
+        );
+        assert_value(
+            "try { 1 | \"2\" } catch e { e }",
+            "bitwise operands must be integers, found string",
+        );
+        assert_value(
+            "try { ~1.5 } catch e { e }",
+            "bitwise operands must be integers, found float",
+        );
+        assert!(run("1 << 64").is_err());
+        assert!(run("1 >> -1").is_err());
+    }
+
+    #[test]
+    fn comparison_and_logic() {
+        assert_value("1 < 2", true);
+        assert_value("2 <= 2.0", true);
+        assert_value("3 > 4", false);
+        assert_value("1 >= 1.5", false);
+        assert_value("1 == 1.0", true);
+        assert_value("\"a\" != \"b\"", true);
+        assert_value("\"abc\" < \"abd\"", true);
+        assert_value("nil == false", false);
+        assert_value("!nil", true);
+        assert_value("!0", false);
+        assert_value("1 + 1 == 2 and 3 > 2", true);
+        assert_value("nil and 1", Value::Nil);
+        assert_value("false or \"x\"", "x");
+        assert_value("1 or missing", 1);
+
+        assert!(run("1 < \"a\"").is_err());
+    }
+
+    #[test]
+    fn if_expressions() {
+        assert_value("if 1 < 2 { \"yes\" } else { \"no\" }", "yes");
+        assert_value("if nil { 1 } else { 2 }", 2);
+        assert_value("if false { 1 }", Value::Nil);
+        assert_value("var x = if true { 10 } else { 20 }; x + 1", 11);
+        assert_value(
+            "var n = 5; if n < 0 { -1 } else if n == 0 { 0 } else { 1 }",
+            1,
+        );
+        assert_value("1 + if false { 1 } else { 2 } * 3", 7);
+        assert_value("var a = 0; if a == 0 { a = 1; } a", 1);
+        assert_value("var a = 0; if a != 0 { a = 1; } else { a = 2; }; a", 2);
+    }
+
+    #[test]
+    fn block_expressions() {
+        assert_value("{ 1 }", 1);
+        assert_value("{ var a = 2; var b = 3; a * b }", 6);
+        assert_value("{ var a = 2; a = 5; }", Value::Nil);
+        assert_value("1 + { var a = 2; a * 3 }", 7);
+        assert_value("var x = { var a = 1; { var b = a + 1; b * 10 } }; x", 20);
+        assert_value(
+            "var r; { var a = 1; var b = 2 + { var c = 3; c + a }; r = a + b; } r",
+            7,
+        );
+        assert_value("{ var a = { var a = 4; a }; a }", 4);
+        assert_value(
+            "{ var a = 1; { var b = if a == 1 { var c = 10; c } else { 0 }; b } }",
+            10,
+        );
+    }
+
+    #[test]
+    fn lists() {
+        assert_value("[1, 2, 3]", vec![1.into(), 2.into(), 3.into()]);
+        assert_value("[]", Vec::new());
+        assert_value("var xs = [1, [2, 3]]; xs[1][0] + xs[-1][-1]", 5);
+        assert_value(
+            "var xs = [1, 2]; xs[0] = 10; xs[-1] += 5; xs[0] + xs[1]",
+            17,
+        );
+        assert_value("var xs = [[1], [2]]; xs[1][0] *= 7; xs[1][0]", 14);
+        assert_value("var xs = [1, 2]; var ys = xs; ys[0] = 5; xs[0]", 5);
+        assert_value("[1, [2.0]] == [1.0, [2]]", true);
+        // Comparing lists that contain themselves stops at the cycle.
+        assert_value(
+            "var a = [nil]; a[0] = a; var b = [nil]; b[0] = b; a == b",
+            true,
+        );
+        assert_value(
+            "var a = [1, nil]; a[1] = a; var b = [2, nil]; b[1] = b; a == b",
+            false,
+        );
+
+        assert!(run("[1][1]").is_err());
+        assert!(run("[1][-2]").is_err());
+        assert!(run("[1][0.0]").is_err());
+        assert!(run("1[0]").is_err());
+    }
+
+    #[test]
+    fn match_literals_and_ranges() {
+        let classify = |n: &str| {
+            format!(
+                "match {n} {{ 0 => \"zero\", -1 => \"minus one\", 1..10 => \"small\", \"ten\" => \"text\", _ => \"other\" }}"
+            )
+        };
+        assert_value(&classify("0"), "zero");
+        assert_value(&classify("-1"), "minus one");
+        assert_value(&classify("1"), "small");
+        assert_value(&classify("9.5"), "small");
+        assert_value(&classify("10"), "other");
+        assert_value(&classify("\"ten\""), "text");
+        assert_value(&classify("nil"), "other");
+
+        assert_value("match true { false => 0, true => 1 }", 1);
+        assert_value("match 3 { 1 => 1, 2 => 2 }", Value::Nil);
+    }
+
+    #[test]
+    fn match_bindings_and_guards() {
+        assert_value("match 5 { n if n > 10 => n, n => n * 2 }", 10);
+        assert_value("match 50 { n if n > 10 => n, n => n * 2 }", 50);
+        assert_value("var y = 1; match 2 { x => x + y }", 3);
+        assert_value(
+            "{ var a = 1; var r = match a + 1 { b => { var c = b * 10; c + a } }; r }",
+            21,
+        );
+        assert_value(
+            "match [1, 2] { [a, b] if a > b => \"desc\", [a, b] if a < b => \"asc\", _ => \"eq\" }",
+            "asc",
+        );
+        assert_value("match 1 { _ if false => 0, _ => 1 }", 1);
+    }
+
+    #[test]
+    fn match_lists() {
+        let describe = |list: &str| {
+            format!(
+                "match {list} {{
+                    [] => \"empty\",
+                    [x] => \"one \" + x,
+                    [\"a\", ..] => \"starts with a\",
+                    [first, .., last] if first == last => \"bookends\",
+                    [_, ..rest] => rest,
+                    _ => \"not a list\",
+                }}"
+            )
+        };
+        assert_value(&describe("[]"), "empty");
+        assert_value(&describe("[\"x\"]"), "one x");
+        assert_value(&describe("[\"a\", \"b\"]"), "starts with a");
+        assert_value(&describe("[\"b\", \"c\", \"b\"]"), "bookends");
+        assert_value(&describe("[1, 2, 3]"), vec![2.into(), 3.into()]);
+        assert_value(&describe("42"), "not a list");
+
+        assert_value(
+            "match [1, [2, 3], 4] { [a, [b, c], d] => a + b + c + d }",
+            10,
+        );
+        assert_value(
+            "match [1, 2, 3, 4] { [a, ..middle, z] => [a, middle, z] }",
+            {
+                let middle = vec![2.into(), 3.into()];
+                vec![1.into(), middle.into(), 4.into()]
+            },
+        );
+        assert_value("match [1, [2]] { [_, [1]] => 0, [_, [x]] => x }", 2);
+        assert_value(
+            "match (1, [2, 3]) { [a, b] => 0, (a, [b, ..]) => a + b, _ => 1 }",
+            3,
+        );
+        assert_value("match ((1)) { (1) => \"one\" }", "one");
+    }
+
+    #[test]
+    fn tuples() {
+        let tuple = |elements: Vec<Value>| Value::new_tuple(elements);
+        assert_value("(1, \"a\")", tuple(vec![1.into(), "a".into()]));
+        assert_value("(1,)", tuple(vec![1.into()]));
+        assert_value("()", tuple(vec![]));
+        assert_value("(1)", 1);
+        assert_value("var t = (1, (2, 3)); t[1][0] + t[-1][-1]", 5);
+        assert_value("(1, 2) == (1.0, 2)", true);
+        assert_value("(1, 2) == [1, 2]", false);
+        assert_eq!(
+            run("[(1,), (), (\"a\", [nil])]").unwrap().to_string(),
+            "[(1,), (), (a, [nil])]"
+        );
+
+        assert!(run("var t = (1, 2); t[0] = 3;").is_err());
+    }
+
+    #[test]
+    fn destructuring_declarations() {
+        assert_value("var (x, y) = (1, 2); x * 10 + y", 12);
+        assert_value("const [first, ..rest] = [1, 2, 3]; [first, rest]", {
+            vec![1.into(), vec![2.into(), 3.into()].into()]
+        });
+        assert_value(
+            "{ var (a, (b, _)) = (1, (2, 3)); var [c] = [4]; a + b + c }",
+            7,
+        );
+        assert_value("{ var (a, ..rest) = (1, 2, 3); rest }", {
+            Value::new_tuple(vec![2.into(), 3.into()])
+        });
+        assert_value("var (x, 2) = (1, 2); x", 1);
+
+        assert!(run("var (x, y) = (1, 2, 3);").is_err());
+        assert!(run("{ var [x] = (1,); }").is_err());
+        assert!(run("var (x, 2) = (1, 3);").is_err());
+    }
+
+    #[test]
+    fn for_loops() {
+        assert_value("var sum = 0; for x in [1, 2, 3] { sum += x; } sum", 6);
+        assert_value("var sum = 0; for x in () { sum += x; } sum", 0);
+        assert_value(
+            "var r = []; for (k, v) in [(\"a\", 1), (\"b\", 2)] { r = [r, k, v]; } r[1] + r[0][1]",
+            "ba",
+        );
+        assert_value(
+            "{ var n = 0; for [a, ..] in [[1], [2, 3]] { for b in (a, a) { n += b; } } n }",
+            6,
+        );
+        assert_value("var xs = [1, 2]; for x in xs { xs[0] += x; } xs[0]", 4);
+
+        assert!(run("for x in 1 {}").is_err());
+        assert!(run("for (a, b) in [(1, 2), 3] {}").is_err());
+    }
+
+    #[test]
+    fn many_constants() {
+        let terms: Vec<String> = (0..300).map(|i| i.to_string()).collect();
+        assert_value(&terms.join(" + "), 44850);
+
+        let chunk = super::compile(r#"[1, 1.0, 1, "a", "a", 1.0]"#).unwrap();
+        assert_eq!(chunk.constants.len(), 3);
+
+        // So do names, functions and variants.
+        let source = format!("{}; var x = 1; x", terms.join(" + "));
+        assert_value(&source, 1);
+
+        let globals: String = (0..200).map(|i| format!("var v{i} = {i};\n")).collect();
+        let source = format!(
+            "{globals}{SHAPES}
+            fn scaled(x, by = 1) {{ x * by }}
+            v199 += 1;
+            var shape = Shape.Circle(v3);
+            var r = match shape {{ Shape.Circle(r) => scaled(r, by: v2), _ => 0 }};
+            r + v199 + 1000"
+        );
+        assert_value(&source, 1206);
+
+        let chunk = super::compile(&source).unwrap();
+        let mut ops = Vec::new();
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let op = OpCode::try_from(chunk.code[offset]).unwrap();
+            ops.push(op);
+            offset += 1 + op.num_operands();
+        }
+        for op in [
+            OpCode::ConstantLong,
+            OpCode::GetGlobalLong,
+            OpCode::DefineGlobalLong,
+            OpCode::SetGlobalLong,
+            OpCode::MakeFunctionLong,
+            OpCode::CallNamedLong,
+            OpCode::BuildVariantLong,
+            OpCode::MatchVariantLong,
+        ] {
+            assert!(ops.contains(&op), "{op}");
+        }
+    }
+
+    #[test]
+    fn functions() {
+        assert_value("fn add(a, b) { a + b } add(1, 2)", 3);
+        assert_value("fn nothing() {} nothing()", Value::Nil);
+        assert_value(
+            "fn fib(n) { if n < 2 { return n; } fib(n - 1) + fib(n - 2) } fib(10)",
+            55,
+        );
+        assert_value(
+            "fn sign(n) { match n { 0 => 0, n if n < 0 => -1, _ => 1 } } [sign(-5), sign(0), sign(2)]",
+            vec![(-1).into(), 0.into(), 1.into()],
+        );
+        assert_value(
+            "{ var x = 1; fn twice(x) { var y = x * 2; y } twice(x + 1) + x }",
+            5,
+        );
+        assert_value("fn f() { return; 1 } f()", Value::Nil);
+        assert_value("fn f(x) { x } var g = f; g == f and f != 1", true);
+        // Functions can call themselves, wherever they're declared.
+        assert_value("fn f(n) { if n == 0 { 0 } else { n + f(n - 1) } } f(3)", 6);
+        assert_value(
+            "{ fn f(n) { if n == 0 { 0 } else { n + f(n - 1) } } f(3) }",
+            6,
+        );
+        assert_value(
+            "fn outer() { fn f(n) { if n == 0 { 0 } else { n + f(n - 1) } } f(4) } outer()",
+            10,
+        );
+        assert_eq!(run("fn f() {} f").unwrap().to_string(), "<fn f>");
+
+        assert!(run("1()").is_err());
+        assert!(run("fn f(a) {} f()").is_err());
+        assert!(run("fn f(a) {} f(1, 2)").is_err());
+        assert!(run("fn f() { f() } f()").is_err());
+    }
+
+    #[test]
+    fn function_parameters() {
+        let greet = "fn greet(name, greeting = \"hello\", punctuation = \"!\") {
+            greeting + \" \" + name + punctuation
+        }";
+        assert_value(&format!("{greet} greet(\"lof\")"), "hello lof!");
+        assert_value(&format!("{greet} greet(\"lof\", \"hi\")"), "hi lof!");
+        assert_value(
+            &format!("{greet} greet(punctuation: \"?\", name: \"lof\")"),
+            "hello lof?",
+        );
+        assert_value(
+            "var d = 1; fn f(x = d) { x } d = 2; [f(), f(3)]",
+            vec![1.into(), 3.into()],
+        );
+
+        assert_value(
+            "fn count(first, ..rest) { [first, rest] } count(1, 2, 3)",
+            vec![1.into(), vec![2.into(), 3.into()].into()],
+        );
+        assert_value("fn count(..all) { all } count()", Vec::new());
+        assert_value(
+            "fn len((x, y), [_, ..rest] = [0]) { x + y + rest[0] } len((\"a\", \"b\"), [1, \"c\"])",
+            "abc",
+        );
+
+        assert!(run(&format!("{greet} greet()")).is_err());
+        assert!(run(&format!("{greet} greet(\"lof\", name: \"x\")")).is_err());
+        assert!(run(&format!("{greet} greet(\"lof\", volume: 11)")).is_err());
+        assert!(run("fn f(..rest) {} f(rest: 1)").is_err());
+        assert!(run("fn f((x, y)) {} f((1, 2, 3))").is_err());
+    }
+
+    #[test]
+    fn exceptions() {
+        assert_value("try { 1 } catch e { 2 }", 1);
+        assert_value(
+            "try { throw \"oops\"; 1 } catch e { [e] }",
+            vec!["oops".into()],
+        );
+        assert_value("try { throw 1; } catch { 2 }", 2);
+        assert_value("try { 1 + nil } catch e { e }", "invalid operands for ADD");
+        assert_value(
+            "try { missing } catch e { e }",
+            "undefined variable 'missing'",
+        );
+        assert_value(
+            "{ var a = 1; var r = try { var b = 2; throw a + b; } catch e { var c = e * 10; c }; r + a }",
+            31,
+        );
+        assert_value(
+            "try { try { throw 1; } catch e { throw e + 1; } } catch e { e }",
+            2,
+        );
+        assert_value(
+            "match try { throw (1, 2); } catch (a, b) { a + b } { n => n }",
+            3,
+        );
+
+        assert!(run("throw 1;").is_err());
+        assert!(run("try { throw 1; } catch { throw 2; }").is_err());
+    }
+
+    #[test]
+    fn exceptions_unwind_calls() {
+        assert_value(
+            "fn check(n) { if n > 2 { throw n; } n }
+             fn sum(xs) { var total = 0; for x in xs { total += check(x); } total }
+             [sum([1, 2]), try { sum([1, 2, 3]) } catch e { -e }]",
+            vec![3.into(), (-3).into()],
+        );
+        assert_value(
+            "fn safe(f) { try { f() } catch e { \"caught \" + e } }
+             fn fails() { 1 < \"a\" }
+             safe(fails)",
+            "caught invalid operands for LESS",
+        );
+    }
+
+    #[test]
+    fn runtime_errors() {
+        let frame = |function: &str, line, column| TraceFrame {
+            function: function.into(),
+            line,
+            column,
+        };
+
+        let error = run("var a = 1;\nvar b = a +\n  \"x\";").unwrap_err();
+        assert_eq!(error.message, "invalid operands for ADD");
+        assert_eq!(error.line, 3);
+        assert_eq!(error.column, 3);
+        assert_eq!(error.trace, vec![frame("script", 3, 3)]);
+
+        let error =
+            run("fn inner(x) {\n  throw x;\n}\nfn outer() {\n  inner(\"oops\")\n}\n\nouter()")
+                .unwrap_err();
+        assert_eq!(error.message, "oops");
+        assert_eq!(error.line, 2);
+        assert_eq!(
+            error.trace,
+            vec![
+                frame("inner", 2, 10),
+                frame("outer", 5, 15),
+                frame("script", 8, 7)
+            ]
+        );
+        assert_eq!(
+            error.to_string(),
+            "oops\n[line 2, column 10] in inner\n[line 5, column 15] in outer\n[line 8, column 7] in script"
+        );
+
+        let error = run("fn f(n) { f(n + 1) } f(0)").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::StackOverflow);
+        assert_eq!(error.trace.len(), 256);
+    }
+
+    #[test]
+    fn stack_limits() {
+        let run_with =
+            |source, config| VM::with_config(super::compile(source).unwrap(), config).run();
+
+        // Deeper than the stack's initial capacity.
+        let list = format!("[{}]", vec!["n"; 255].join(", "));
+        assert_value(
+            &format!("fn f(n) {{ if n == 0 {{ 0 }} else {{ {list}[0] + f(n - 1) }} }} f(3)"),
+            6,
+        );
+
+        let small = Config {
+            max_stack: 8,
+            ..Config::default()
+        };
+        let error = run_with(
+            "fn f() {\n  [1, 2, 3, 4, 5, 6, 7, 8]\n}\nf()",
+            small.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(error.kind, ErrorKind::StackOverflow);
+        assert_eq!(error.message, "stack overflow");
+        assert_eq!(
+            error.trace,
+            vec![
+                TraceFrame {
+                    function: "f".into(),
+                    line: 2,
+                    column: 22,
+                },
+                TraceFrame {
+                    function: "script".into(),
+                    line: 4,
+                    column: 3,
+                },
+            ]
+        );
+        assert_eq!(
+            run_with(
+                "try { [1, 2, 3, 4, 5, 6, 7, 8] } catch e { e == RuntimeError.StackOverflow }",
+                small.clone()
+            ),
+            Ok(true.into())
+        );
+        // Rethrowing it keeps its kind.
+        let error = run_with(
+            "try { [1, 2, 3, 4, 5, 6, 7, 8] } catch e { throw e; }",
+            small,
+        )
+        .unwrap_err();
+        assert_eq!(error.kind, ErrorKind::StackOverflow);
+        assert_eq!(
+            run(r#"try { throw "stack overflow"; } catch e { throw e; }"#)
+                .unwrap_err()
+                .kind,
+            ErrorKind::Uncaught
+        );
+
+        let shallow = Config {
+            max_frames: 4,
+            ..Config::default()
+        };
+        let error = run_with("fn f(n) { f(n + 1) } f(0)", shallow).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::StackOverflow);
+        assert_eq!(error.trace.len(), 4);
+    }
+
+    #[test]
+    fn garbage_collection() {
+        let stress = Config {
+            stress_gc: true,
+            ..Config::default()
+        };
+        let sources: [(&str, Value); 3] = [
+            (
+                "var xs = []; for i in [1, 2, 3] { xs = [xs, [i]]; } xs[1][0] + xs[0][1][0]",
+                5.into(),
+            ),
+            (
+                "fn f(..xs) { var [_, ..rest] = xs; [xs, rest] } var r = f(1, 2, 3); r[1][0] + r[0][0]",
+                3.into(),
+            ),
+            (
+                "var r = try { throw [[1], 2]; } catch [a, b] { a[0] + b }; r",
+                3.into(),
+            ),
+        ];
+        let incremental = Config {
+            gc_mode: GcMode::Incremental { budget: 1 },
+            ..stress.clone()
+        };
+        for (source, expected) in sources {
+            for config in [&stress, &incremental] {
+                let mut vm = VM::with_config(super::compile(source).unwrap(), config.clone());
+                assert_eq!(vm.run(), Ok(expected.clone()), "{source}");
+                assert!(vm.heap.stats.collections > 0, "{source}");
+                assert!(vm.heap.stats.pauses >= vm.heap.stats.collections);
+            }
+        }
+
+        // Marking `big` takes long enough for `keep` to be marked before
+        // most of the lists stored in it, which are then only reachable
+        // from there.
+        let source = format!(
+            "var big = [{}]; var keep = [[0]]; var n = 0;
+             for a in {digits} {{ for b in {digits} {{ n += keep[0][0]; keep[0] = [b]; }} }}
+             n",
+            vec!["[0]"; 40].join(", "),
+            digits = "[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]",
+        );
+        let mut vm = VM::with_config(super::compile(&source).unwrap(), incremental);
+        assert_eq!(vm.run(), Ok(540.into()));
+
+        let cycle = "var a = [1, nil]; a[1] = a; [a]";
+        assert_eq!(run(cycle).unwrap().to_string(), "[[1, [...]]]");
+
+        let mut vm = VM::new(super::compile("var a = [1, nil]; a[1] = a; a = [a]; nil").unwrap());
+        vm.run().unwrap();
+        vm.collect_garbage();
+        assert_eq!((vm.heap.objects(), vm.heap.stats.freed), (2, 0));
+
+        vm.interpret(super::compile("a = nil; nil").unwrap())
+            .unwrap();
+        vm.collect_garbage();
+        assert_eq!((vm.heap.objects(), vm.heap.stats.freed), (0, 1));
+    }
+
+    #[test]
+    fn heap_limits() {
+        let limited = Config {
+            max_heap: Some(10_000),
+            ..Config::default()
+        };
+        let ten = "[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]";
+        let run_with = |source: &str| {
+            let mut vm = VM::with_config(super::compile(source).unwrap(), limited.clone());
+            (vm.run(), vm.heap)
+        };
+
+        // Garbage is collected to make room.
+        let (result, heap) = run_with(&format!(
+            "var n = 0; for a in {ten} {{ for b in {ten} {{ var tmp = {ten}; n += tmp[a - 1]; }} }} n"
+        ));
+        assert_eq!(result, Ok(550.into()));
+        assert!(heap.stats.collections > 0);
+        assert_eq!(heap.stats.pauses, heap.stats.collections);
+        assert!(heap.peak_bytes() <= 10_000 && heap.peak_bytes() >= heap.bytes_in_use());
+
+        let keep_everything =
+            format!("for a in {ten} {{ for b in {ten} {{ keep = [keep, {ten}]; }} }}");
+        let (result, _) = run_with(&format!("var keep = nil; {keep_everything}"));
+        let error = result.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::OutOfMemory);
+        assert_eq!(error.message, "out of memory");
+
+        let (result, heap) = run_with(&format!(
+            "var keep = nil; try {{ {keep_everything} }} catch e {{ keep = nil; e == RuntimeError.OutOfMemory }}"
+        ));
+        assert_eq!(result, Ok(true.into()));
+        assert!(heap.peak_bytes() <= 10_000);
+
+        let mut vm =
+            VM::new(super::compile(&format!("var keep = nil; {keep_everything}")).unwrap());
+        vm.run().unwrap();
+        assert!(vm.heap.bytes_in_use() > 10_000);
+    }
+
+    #[test]
+    fn fuel() {
+        let source = "fn f(n) { if n < 2 { n } else { f(n - 1) + f(n - 2) } } f(10)";
+        let vm = || VM::new(super::compile(source).unwrap());
+
+        let mut full = vm();
+        assert_eq!(
+            full.run_with_fuel(u64::MAX),
+            Ok(RunState::Finished(55.into()))
+        );
+        let cost = u64::MAX - full.fuel().unwrap();
+
+        let mut short = vm();
+        assert_eq!(short.run_with_fuel(cost - 1), Ok(RunState::OutOfFuel));
+        short.add_fuel(1);
+        assert_eq!(short.resume(), Ok(RunState::Finished(55.into())));
+        assert_eq!(short.fuel(), Some(0));
+
+        // Stopping and starting again anywhere makes no difference.
+        let mut steps = vm();
+        let mut pauses = 0;
+        let mut state = steps.run_with_fuel(7);
+        while state == Ok(RunState::OutOfFuel) {
+            pauses += 1;
+            steps.add_fuel(7);
+            state = steps.resume();
+        }
+        assert_eq!(state, Ok(RunState::Finished(55.into())));
+        assert!(pauses as u64 >= cost / 7);
+
+        assert_eq!(vm().run_with_fuel(0), Ok(RunState::OutOfFuel));
+    }
+
+    #[test]
+    fn interrupts() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let mut vm = VM::new(super::compile("fn f() {\n  1\n}\nvar x = 1;\nf()").unwrap());
+        let handle = vm.interrupt_handle();
+        assert_send_sync(&handle);
+        handle.interrupt();
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Interrupted);
+        assert_eq!(error.line, 5);
+
+        // Handlers don't see it, and it only happens once.
+        let ten = "[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]";
+        let source = format!(
+            "try {{ for a in {ten} {{ for b in {ten} {{ for c in {ten} {{ for d in {ten} {{
+               for e in {ten} {{ for f in {ten} {{ for g in {ten} {{ }} }} }} }} }} }} }} }} catch {{ 1 }}"
+        );
+        let mut vm = VM::new(super::compile(&source).unwrap());
+        let handle = vm.interrupt_handle();
+        let watchdog = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            handle.interrupt();
+        });
+        let error = vm.run().unwrap_err();
+        watchdog.join().unwrap();
+        assert_eq!(error.kind, ErrorKind::Interrupted);
+        assert_eq!(error.trace.len(), 1);
+        assert_eq!(vm.interpret(super::compile("1 + 1").unwrap()), Ok(2.into()));
+    }
+
+    #[test]
+    fn weak_references() {
+        assert_value("var a = [1]; var w = weak(a); w()[0]", 1);
+        assert_value("var w = weak([1]); w()", Value::Nil);
+        assert_value(
+            "try { weak(weak) } catch e { e }",
+            "weak() expects an object, found <native fn weak>",
+        );
+        assert_value(
+            "try { weakmap(1) } catch e { e }",
+            "weakmap() takes 0 arguments but 1 were given",
+        );
+        assert_value(
+            "var m = weakmap(); var k = [1]; m[k] = 2; m[(k,)] = 3; [m[k], m[[1]], m[m]]",
+            vec![2.into(), Value::Nil, Value::Nil],
+        );
+
+        let mut vm = VM::new(
+            super::compile(
+                "var a = [nil]; a[0] = a; var w = weak(a);
+                 var m = weakmap(); var k = [1]; m[k] = [k]; m[a] = 1;
+                 a = nil; k = nil;
+                 w() != nil",
+            )
+            .unwrap(),
+        );
+        assert_eq!(vm.run(), Ok(true.into()));
+        vm.collect_garbage();
+        assert_eq!(vm.interpret(super::compile("w()").unwrap()), Ok(Value::Nil));
+        let Some(Value::WeakMap(map)) = vm.globals.get("m") else {
+            panic!("m should be a weak map");
+        };
+        assert!(map.borrow().entries.is_empty());
+    }
+
+    #[test]
+    fn finalizers() {
+        assert_value(
+            "try { finalize(1, finalize) } catch e { e }",
+            "finalize() expects a function, found <native fn finalize>",
+        );
+        assert_value(
+            "fn f() {} try { finalize(1, f) } catch e { e }",
+            "finalize() expects an object, found 1",
+        );
+
+        // Finalizers run as soon as a collection finds their object
+        // unreachable, even when they throw, and the code they interrupt
+        // carries on.
+        let stress = Config {
+            stress_gc: true,
+            ..Config::default()
+        };
+        let incremental = Config {
+            gc_mode: GcMode::Incremental { budget: 1 },
+            ..stress.clone()
+        };
+        let source = "var log = \"\";
+             fn cycle() { log += \"c\"; throw \"ignored\"; }
+             fn kept() { log += \"k\"; }
+             var a = [nil]; a[0] = a; finalize(a, cycle);
+             var b = [1]; finalize(b, kept);
+             a = nil;
+             var c = nil;
+             for i in [1, 2, 3, 4, 5, 6, 7, 8, 9, 10] { c = [i]; }
+             [log, c[0] + b[0]]";
+        for config in [stress, incremental] {
+            let mut vm = VM::with_config(super::compile(source).unwrap(), config);
+            assert_eq!(vm.run(), Ok(Value::new_list(vec!["c".into(), 11.into()])));
+        }
+
+        // Ones that come due outside a run are called before the next one.
+        let mut vm = VM::new(
+            super::compile("var done = false; fn f() { done = true; } finalize([1], f);").unwrap(),
+        );
+        assert_eq!(vm.run(), Ok(Value::Nil));
+        vm.collect_garbage();
+        assert_eq!(
+            vm.interpret(super::compile("done").unwrap()),
+            Ok(true.into())
+        );
+    }
 
-        compiler.compile().unwrap();
+    #[test]
+    fn finally_blocks() {
+        assert_value(
+            "var log = []; var r = try { 1 } finally { log = [log, \"f\"]; }; [r, log[1]]",
+            vec![1.into(), "f".into()],
+        );
+        assert_value(
+            "var log = \"\"; var r = try { throw 1; } catch { log += \"c\"; 2 } finally { log += \"f\"; }; [r, log]",
+            vec![2.into(), "cf".into()],
+        );
+        assert_value(
+            "var log = \"\"; try { try { throw \"x\"; } finally { log += \"f\"; } } catch e { log + e }",
+            "fx",
+        );
+        assert_value(
+            "var log = \"\"; fn f() { try { return 1; } finally { log += \"f\"; } 2 } [f(), log]",
+            vec![1.into(), "f".into()],
+        );
+        assert_value(
+            "var log = \"\";
+             fn f() { try { try { return 1; } finally { log += \"a\"; } } finally { log += \"b\"; } }
+             [f(), log]",
+            vec![1.into(), "ab".into()],
+        );
+        assert_value("fn f() { try { return 1; } finally { return 2; } } f()", 2);
+
+        assert!(run("try { throw 1; } finally {}").is_err());
+    }
+
+    const SHAPES: &str = "enum Shape { Empty, Circle(radius), Rect(width, height) }";
 
-        let chunk = compiler.compiling_chunk;
+    #[test]
+    fn enums() {
+        assert_value(&format!("{SHAPES} Shape.Empty == Shape.Empty"), true);
+        assert_value(
+            &format!("{SHAPES} Shape.Circle(1) == Shape.Circle(1.0)"),
+            true,
+        );
+        assert_value(
+            &format!("{SHAPES} Shape.Circle(1) != Shape.Circle(2)"),
+            true,
+        );
+        assert_value(
+            &format!("{SHAPES} Shape.Rect(1, 2) == Shape.Circle(1)"),
+            false,
+        );
+        assert_value(&format!("{SHAPES} Shape.Empty == nil"), false);
+        assert_value(&format!("{SHAPES} {{ var Shape = 1; Shape }}"), 1);
+
+        let shape = run(&format!("{SHAPES} [Shape.Empty, Shape.Rect(2, [3])]")).unwrap();
+        assert_eq!(shape.to_string(), "[Shape.Empty, Shape.Rect(2, [3])]");
+    }
+
+    #[test]
+    fn match_enums() {
+        let area = |shape: &str| {
+            format!(
+                "{SHAPES}
+                match {shape} {{
+                    Shape.Empty => 0,
+                    Shape.Circle(0) => \"dot\",
+                    Shape.Circle(r) => 3 * r * r,
+                    Shape.Rect(w, h) if w == h => [\"square\", w],
+                    Shape.Rect(w, h) => w * h,
+                }}"
+            )
+        };
+        assert_value(&area("Shape.Empty"), 0);
+        assert_value(&area("Shape.Circle(0)"), "dot");
+        assert_value(&area("Shape.Circle(2)"), 12);
+        assert_value(&area("Shape.Rect(2, 2)"), vec!["square".into(), 2.into()]);
+        assert_value(&area("Shape.Rect(2, 3)"), 6);
+        assert_value(&area("[1]"), Value::Nil);
+
+        assert_value(
+            &format!(
+                "{SHAPES} match [Shape.Circle(Shape.Empty)] {{ [Shape.Circle(Shape.Empty)] => 1 }}"
+            ),
+            1,
+        );
+        assert_value(
+            &format!("{SHAPES} match Shape.Rect(1, 2) {{ Shape.Rect => 1 }}"),
+            1,
+        );
+    }
+
+    #[test]
+    fn non_exhaustive_matches() {
+        let warnings = |arms: &str| {
+            let source = format!("{SHAPES}\nmatch Shape.Empty {{ {arms} }}");
+            super::compile_with_warnings(&source).unwrap().1
+        };
+        let missing = |missing: &[&str]| {
+            vec![CompilerWarning::NonExhaustiveMatch {
+                line: 2,
+                missing: missing.iter().map(|name| name.to_string()).collect(),
+            }]
+        };
+
+        assert_eq!(
+            warnings("Shape.Empty => 0, Shape.Circle(r) => r, Shape.Rect => 1"),
+            []
+        );
+        assert_eq!(warnings("Shape.Empty => 0, _ => 1"), []);
+        assert_eq!(warnings("1 => 1"), []);
+        assert_eq!(
+            warnings("Shape.Empty => 0"),
+            missing(&["Shape.Circle", "Shape.Rect"])
+        );
+        assert_eq!(
+            warnings("Shape.Circle(1) => 0, Shape.Rect(w, h) if w > h => 1, x => 2"),
+            []
+        );
+        assert_eq!(
+            warnings("Shape.Circle(1) => 0, Shape.Rect(w, h) if w > h => 1, Shape.Empty => 2"),
+            missing(&["Shape.Circle", "Shape.Rect"])
+        );
+    }
+
+    #[test]
+    fn results() {
+        assert_value("Ok(1) == Ok(1.0) and Ok(1) != Err(1)", true);
+        assert_eq!(
+            run("[Ok(1), Err(\"no\")]").unwrap().to_string(),
+            "[Result.Ok(1), Result.Err(no)]"
+        );
+        assert_value(
+            "fn describe(r) { match r { Ok(n) if n > 9 => \"big\", Ok(_) => \"small\", Err(e) => e } }
+             [describe(Ok(10)), describe(Ok(1)), describe(Err(\"bad\"))]",
+            vec!["big".into(), "small".into(), "bad".into()],
+        );
+        assert_value("{ var Ok = 1; Ok + 1 }", 2);
+        assert_eq!(
+            super::compile_with_warnings("match Ok(1) { Ok(x) => x }")
+                .unwrap()
+                .1,
+            [CompilerWarning::NonExhaustiveMatch {
+                line: 1,
+                missing: vec!["Result.Err".to_string()],
+            }]
+        );
+    }
+
+    #[test]
+    fn propagating_errors() {
+        let parse = "fn parse(n) { if n < 0 { Err(\"negative\") } else { Ok(n * 2) } }";
+        assert_value(
+            &format!("{parse} fn sum(a, b) {{ Ok(parse(a)? + parse(b)?) }} sum(1, 2)"),
+            Value::ok(6.into()),
+        );
+        assert_value(
+            &format!("{parse} fn sum(a, b) {{ Ok(parse(a)? + parse(b)?) }} sum(1, -2)"),
+            Value::err("negative".into()),
+        );
+        assert_value(
+            &format!(
+                "{parse} var log = \"\";
+                 fn f() {{ try {{ parse(-1)?; }} finally {{ log += \"f\"; }} Ok(0) }}
+                 [f(), log]"
+            ),
+            vec![Value::err("negative".into()), "f".into()],
+        );
+        assert_value(
+            &format!("{parse} parse(-1)?; 1"),
+            Value::err("negative".into()),
+        );
+        assert_value("[Ok([1, 2])?[1]]", vec![2.into()]);
 
-        dbg!(chunk);
+        assert!(run("1?").is_err());
+    }
+
+    #[test]
+    fn match_as_statement() {
+        assert_value(
+            "var total = 0; match [1, 2] { [a, b] => { total = a + b; } } total",
+            3,
+        );
+    }
+
+    #[test]
+    fn variables() {
+        assert_value("var a = 1; var b = a + 2; b * 10", 30);
+        assert_value("var a; a", Value::Nil);
+        assert_value("var a = 1; a = a + 1; a", 2);
+        assert_value("var a = 1; var b = a = 5; a + b", 10);
+        assert_value("var a = 1; { var a = 2; a = a * 10; } a", 1);
+        assert_value("var r; { var a = 2; { var b = a + 1; r = b; } } r", 3);
+        assert_value("const n = 10; var r; { const local = n; r = local; } r", 10);
+    }
+
+    #[test]
+    fn compound_assignment() {
+        assert_value("var a = 10; a += 5; a", 15);
+        assert_value("var a = 10; a -= 5; a", 5);
+        assert_value("var a = 10; a *= 5; a", 50);
+        assert_value("var a = 10; a /= 4; a", 2.5);
+        assert_value("var a = 10; a %= 4; a", 2);
+        assert_value("var a = 2; var b = a += 1; a * b", 9);
+        assert_value("var r; { var a = 3; a += a *= 2; r = a; } r", 9);
+        assert_value("var s = \"a\"; s += \"b\"; s", "ab");
+    }
+
+    #[test]
+    fn malformed_expressions() {
+        for (source, error) in [
+            ("(1 + 2", CompilerError::UnexpectedEOF),
+            ("1 2", CompilerError::ExpectedToken(TokenKind::Semicolon)),
+            ("1 + * 2", CompilerError::ExpectedExpression),
+        ] {
+            assert_eq!(super::compile(source).unwrap_err(), error, "{source}");
+        }
+    }
+
+    #[test]
+    fn invalid_assignments() {
+        for (source, error) in [
+            (
+                "var a; var b; a + b = 1;",
+                CompilerError::InvalidAssignmentTarget,
+            ),
+            ("var a; 1 += a;", CompilerError::InvalidAssignmentTarget),
+            ("const a = 1; a = 2;", CompilerError::AssignToConstant),
+            ("{ const a = 1; a *= 2; }", CompilerError::AssignToConstant),
+            ("const a;", CompilerError::MissingInitializer),
+            ("const a = 1; var a = 2;", CompilerError::AlreadyDeclared),
+            ("{ var a = 1; var a = 2; }", CompilerError::AlreadyDeclared),
+            ("{ var a = a; }", CompilerError::ReadInOwnInitializer),
+            (
+                "{ var a = 1; { var a = a + 1; } }",
+                CompilerError::ReadInOwnInitializer,
+            ),
+            ("enum E { A } var E = 1;", CompilerError::AlreadyDeclared),
+            ("enum Result { A }", CompilerError::AlreadyDeclared),
+            ("var Err = 1;", CompilerError::AlreadyDeclared),
+            ("Ok(1, 2)", CompilerError::WrongPayloadCount),
+            ("var (a, a) = (1, 2);", CompilerError::AlreadyDeclared),
+            ("fn f(a, (b, a)) {}", CompilerError::AlreadyDeclared),
+            ("fn f() {} f() = 1;", CompilerError::InvalidAssignmentTarget),
+            ("fn f(a) {} f(a: 1, 2)", CompilerError::PositionalAfterNamed),
+            ("{ var x = 1; fn f() { x } }", CompilerError::EnclosingLocal),
+            (
+                "fn f(n) { fn g() { n } g() }",
+                CompilerError::EnclosingLocal,
+            ),
+            ("{ fn f() { fn g() { f } } }", CompilerError::EnclosingLocal),
+            ("{ fn f() { f = 1; } }", CompilerError::AssignToConstant),
+            ("try { 1 }", CompilerError::ExpectedToken(TokenKind::Catch)),
+            (
+                "const (a, b) = (1, 2); b = 3;",
+                CompilerError::AssignToConstant,
+            ),
+            ("enum E { A, A }", CompilerError::AlreadyDeclared),
+            ("enum E { A } E.B", CompilerError::UnknownVariant),
+            (
+                "enum E { A(x) } E.A(1, 2)",
+                CompilerError::WrongPayloadCount,
+            ),
+            (
+                "enum E { A(x) } match 1 { E.A() => 1 }",
+                CompilerError::WrongPayloadCount,
+            ),
+            ("match 1 { F.A => 1 }", CompilerError::UnknownEnum),
+            (
+                "enum E { A } E.A = 1;",
+                CompilerError::InvalidAssignmentTarget,
+            ),
+        ] {
+            assert_eq!(super::compile(source).unwrap_err(), error, "{source}");
+        }
     }
 }
@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod compiler;
 pub mod scanner;
 pub mod token;
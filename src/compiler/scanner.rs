@@ -1,6 +1,10 @@
 use lazy_static::lazy_static;
-use std::{collections::BTreeMap, str::CharIndices};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    str::CharIndices,
+};
 use thiserror::Error;
+use unicode_xid::UnicodeXID;
 
 use crate::compiler::token::{LiteralValue, Token, TokenKind};
 
@@ -9,8 +13,14 @@ lazy_static! {
         ("and", TokenKind::And),
         ("or", TokenKind::Or),
         ("class", TokenKind::Class),
+        ("enum", TokenKind::Enum),
         ("if", TokenKind::If),
         ("else", TokenKind::Else),
+        ("match", TokenKind::Match),
+        ("try", TokenKind::Try),
+        ("catch", TokenKind::Catch),
+        ("finally", TokenKind::Finally),
+        ("throw", TokenKind::Throw),
         ("true", TokenKind::True),
         ("false", TokenKind::False),
         ("fn", TokenKind::Fn),
@@ -32,6 +42,16 @@ pub enum ScannerError {
     UnexpectedToken,
     #[error("unterminated string")]
     UnterminatedString,
+    #[error("numbers can't start with '.', write '0.5' instead of '.5'")]
+    LeadingDot,
+    #[error("expected digits after the base prefix")]
+    MissingDigits,
+    #[error("expected digits in the exponent")]
+    MissingExponent,
+    #[error("invalid digit for a base {0} literal")]
+    InvalidDigit(u32),
+    #[error("integer literal is too large")]
+    IntegerOverflow,
     #[error("eof")]
     Eof,
 }
@@ -39,71 +59,91 @@ pub enum ScannerError {
 pub type ScannerResult<'source> = Result<Token<'source>, ScannerError>;
 
 #[derive(Debug)]
-pub struct ScannerBuilder<'source> {
-    pub tokens: Vec<ScannerResult<'source>>,
+struct ScannerBuilder<'source> {
+    tokens: VecDeque<ScannerResult<'source>>,
     source: &'source str,
     chars: CharIndices<'source>,
     start: usize,
     current: usize,
     line: usize,
+    /// Where the current line starts in the source.
+    line_start: usize,
     current_id: usize,
+    /// The kind of the last token, which decides whether a `.` can start a
+    /// number.
+    previous: Option<TokenKind>,
 }
 
+/// Scans tokens on demand. Only as many tokens as have been peeked at are
+/// buffered, so memory use is bounded by the lookahead rather than the source.
+#[derive(Debug)]
 pub struct Scanner<'source> {
-    tokens: Vec<ScannerResult<'source>>,
-    source: &'source str,
+    builder: ScannerBuilder<'source>,
 }
 
 impl<'source> Scanner<'source> {
     pub fn new<S: Into<&'source str>>(source: S) -> Self {
         let source = source.into();
-        let mut builder = ScannerBuilder {
-            source,
-            chars: source.char_indices(),
-            tokens: vec![],
-            start: 0,
-            current: 0,
-            line: 0,
-            current_id: 0,
-        };
-
-        builder.scan_tokens();
-
-        builder.tokens.reverse();
-
         Self {
-            tokens: builder.tokens,
-            source,
+            builder: ScannerBuilder {
+                source,
+                chars: source.char_indices(),
+                tokens: VecDeque::new(),
+                start: 0,
+                current: 0,
+                line: 1,
+                line_start: 0,
+                current_id: 0,
+                previous: None,
+            },
         }
     }
 
-    pub fn next(&mut self) -> ScannerResult<'source> {
-        self.tokens.pop().unwrap_or(Err(ScannerError::Eof))
+    /// Consumes the next token. Returns `Err(ScannerError::Eof)` once the
+    /// source is exhausted, and keeps returning it on subsequent calls.
+    pub fn next_token(&mut self) -> ScannerResult<'source> {
+        self.fill(0);
+        self.builder
+            .tokens
+            .pop_front()
+            .unwrap_or(Err(ScannerError::Eof))
+    }
+
+    pub fn peek(&mut self) -> ScannerResult<'source> {
+        self.peek_nth(0)
     }
 
-    pub fn peek(&self) -> ScannerResult<'source> {
-        self.tokens
-            .last()
+    /// Looks `n` tokens ahead without consuming anything, `peek_nth(0)` being
+    /// the token the next call to `next_token` will return.
+    pub fn peek_nth(&mut self, n: usize) -> ScannerResult<'source> {
+        self.fill(n);
+        self.builder
+            .tokens
+            .get(n)
             .cloned()
             .unwrap_or(Err(ScannerError::Eof))
     }
-}
 
-impl<'source> ScannerBuilder<'source> {
-    fn make_eof(&self) -> ScannerResult<'static> {
-        Err(ScannerError::Eof)
+    fn fill(&mut self, n: usize) {
+        while self.builder.tokens.len() <= n && !self.builder.is_at_end() {
+            self.builder.start = self.builder.current;
+            self.builder.scan_token();
+        }
     }
+}
+
+impl<'source> Iterator for Scanner<'source> {
+    type Item = ScannerResult<'source>;
 
-    fn scan_tokens(&mut self) {
-        while !self.is_at_end() {
-            self.start = self.current;
-            self.scan_token();
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_token() {
+            Err(ScannerError::Eof) => None,
+            token => Some(token),
         }
-        self.current_id += 1;
-        let eof = self.make_eof();
-        self.tokens.push(eof);
     }
+}
 
+impl<'source> ScannerBuilder<'source> {
     fn scan_token(&mut self) {
         let c = self.advance().unwrap();
 
@@ -115,32 +155,48 @@ impl<'source> ScannerBuilder<'source> {
             '{' => self.add_token(TokenKind::LeftCurly),
             '}' => self.add_token(TokenKind::RightCurly),
             ',' => self.add_token(TokenKind::Comma),
-            '-' => self.add_token(TokenKind::Minus),
-            '+' => self.add_token(TokenKind::Plus),
+            ':' => self.add_token(TokenKind::Colon),
+            '-' => self.add_token_lookahead('=', TokenKind::MinusEqual, TokenKind::Minus),
+            '+' => self.add_token_lookahead('=', TokenKind::PlusEqual, TokenKind::Plus),
             ';' => self.add_token(TokenKind::Semicolon),
-            '*' => self.add_token(TokenKind::Star),
+            '*' if self.matches('*') => self.add_token(TokenKind::StarStar),
+            '*' => self.add_token_lookahead('=', TokenKind::StarEqual, TokenKind::Star),
+            '%' => self.add_token_lookahead('=', TokenKind::PercentEqual, TokenKind::Percent),
+            '~' => self.add_token_lookahead('/', TokenKind::TildeSlash, TokenKind::Tilde),
+            '&' => self.add_token(TokenKind::Ampersand),
+            '|' => self.add_token(TokenKind::Pipe),
+            '^' => self.add_token(TokenKind::Caret),
+            '?' => self.add_token(TokenKind::Question),
             '!' => self.add_token_lookahead('=', TokenKind::BangEqual, TokenKind::Bang),
+            '.' if !self.after_operand()
+                && self.peek_char().is_some_and(ScannerBuilder::is_digit) =>
+            {
+                self.leading_dot()
+            }
             '.' => self.add_token_lookahead('.', TokenKind::DotDot, TokenKind::Dot),
+            '=' if self.matches('>') => self.add_token(TokenKind::FatArrow),
             '=' => self.add_token_lookahead('=', TokenKind::EqualEqual, TokenKind::Equal),
+            '<' if self.matches('<') => self.add_token(TokenKind::LessLess),
             '<' => self.add_token_lookahead('=', TokenKind::LessEqual, TokenKind::Less),
+            '>' if self.matches('>') => self.add_token(TokenKind::GreaterGreater),
             '>' => self.add_token_lookahead('=', TokenKind::GreaterEqual, TokenKind::Greater),
-            '/' => {
-                if self.matches('/') {
-                    while self.peek_char() != Some('\n') && !self.is_at_end() {
-                        self.advance();
-                    }
-                } else {
-                    self.add_token(TokenKind::Slash)
+            '/' if self.matches('/') => {
+                while self.peek_char() != Some('\n') && !self.is_at_end() {
+                    self.advance();
                 }
             }
+            '/' => self.add_token_lookahead('=', TokenKind::SlashEqual, TokenKind::Slash),
             ' ' => {}
             '\r' => {}
             '\t' => {}
-            '\n' => self.line += 1,
+            '\n' => {
+                self.line += 1;
+                self.line_start = self.current;
+            }
             '"' => self.string(),
 
-            '0'..='9' => self.number(),
-            'a'..='z' | 'A'..='Z' | '_' => self.identifier(),
+            '0'..='9' => self.number(c),
+            c if ScannerBuilder::is_identifier_start(c) => self.identifier(),
 
             _ => self.add_error(ScannerError::UnexpectedToken),
         };
@@ -150,7 +206,7 @@ impl<'source> ScannerBuilder<'source> {
         match self.chars.next() {
             None => None,
             Some((pos, c)) => {
-                self.current = pos + 1;
+                self.current = pos + c.len_utf8();
                 Some(c)
             }
         }
@@ -161,27 +217,24 @@ impl<'source> ScannerBuilder<'source> {
     }
 
     fn peek_next(&self) -> Option<char> {
-        let mut temp = self.chars.clone();
-        temp.next();
-        temp.next().map(|t| t.1)
+        self.peek_nth_char(1)
+    }
+
+    fn peek_nth_char(&self, n: usize) -> Option<char> {
+        self.chars.clone().nth(n).map(|t| t.1)
     }
 
     fn current_slice(&self) -> &'source str {
-        let byte_slice = &self.source.as_bytes()[self.start..self.current];
-        std::str::from_utf8(byte_slice).unwrap()
+        &self.source[self.start..self.current]
     }
 
     fn matches(&mut self, expected: char) -> bool {
         match self.peek_char() {
-            None => false,
-            Some(c) => {
-                if c == expected {
-                    self.advance();
-                    true
-                } else {
-                    false
-                }
+            Some(c) if c == expected => {
+                self.advance();
+                true
             }
+            _ => false,
         }
     }
 
@@ -198,19 +251,31 @@ impl<'source> ScannerBuilder<'source> {
     }
 
     fn add_literal_token(&mut self, kind: TokenKind, value: LiteralValue) {
-        let lexeme = self.current_slice().into();
+        let lexeme = self.current_slice();
         self.current_id += 1;
-        self.tokens.push(Ok(Token {
+        self.previous = Some(kind);
+        self.tokens.push_back(Ok(Token {
             kind,
             lexeme,
             literal: value,
             line: self.line,
+            column: self.source[self.line_start..self.start].chars().count() + 1,
             id: self.current_id,
         }))
     }
 
     fn add_error(&mut self, err: ScannerError) {
-        self.tokens.push(Err(err));
+        self.previous = None;
+        self.tokens.push_back(Err(err));
+    }
+
+    /// Whether the last token ends an operand, after which `.0` is a `.`
+    /// and a `0`, as in `t.0`, rather than a number missing its `0`.
+    fn after_operand(&self) -> bool {
+        matches!(
+            self.previous,
+            Some(TokenKind::Identifier | TokenKind::RightParen | TokenKind::RightBrace)
+        )
     }
 
     fn is_at_end(&self) -> bool {
@@ -229,55 +294,121 @@ impl<'source> ScannerBuilder<'source> {
         // closing "
         self.advance();
 
-        let byte_slice = &self.source.as_bytes()[self.start + 1..self.current - 1];
-        let value = std::str::from_utf8(byte_slice).unwrap().into();
+        // Both quotes are a single byte, so this stays on char boundaries.
+        let value = self.source[self.start + 1..self.current - 1].into();
 
         self.add_literal_token(TokenKind::Literal, LiteralValue::String(value));
     }
 
     fn is_digit(c: char) -> bool {
-        c.is_digit(10)
+        c.is_ascii_digit()
+    }
+
+    fn is_identifier_start(c: char) -> bool {
+        c.is_xid_start() || c == '_'
     }
 
     fn is_identifier_char(c: char) -> bool {
-        c.is_alphabetic() || c == '_'
+        c.is_xid_continue()
     }
 
-    fn consume_digits(&mut self) {
-        while self.peek_char().map_or(false, ScannerBuilder::is_digit) {
+    fn is_digit_or_separator(c: char) -> bool {
+        ScannerBuilder::is_digit(c) || c == '_'
+    }
+
+    fn consume_while(&mut self, predicate: fn(char) -> bool) {
+        while self.peek_char().is_some_and(predicate) {
             self.advance();
         }
     }
 
-    fn number(&mut self) {
+    /// The current lexeme with `_` digit separators stripped.
+    fn current_digits(&self) -> String {
+        self.current_slice().chars().filter(|&c| c != '_').collect()
+    }
+
+    fn number(&mut self, first: char) {
+        let radix = match (first, self.peek_char()) {
+            ('0', Some('x')) => Some(16),
+            ('0', Some('o')) => Some(8),
+            ('0', Some('b')) => Some(2),
+            _ => None,
+        };
+
+        if let Some(radix) = radix {
+            self.advance();
+            return self.radix_integer(radix);
+        }
+
         let mut is_float = false;
 
-        self.consume_digits();
+        self.consume_while(ScannerBuilder::is_digit_or_separator);
 
-        if self.peek_char() == Some('.') && self.peek_next().map_or(false, ScannerBuilder::is_digit)
-        {
+        if self.peek_char() == Some('.') && self.peek_next().is_some_and(ScannerBuilder::is_digit) {
+            is_float = true;
+            self.advance();
+            self.consume_while(ScannerBuilder::is_digit_or_separator);
+        }
+
+        if matches!(self.peek_char(), Some('e' | 'E')) {
             is_float = true;
             self.advance();
-            self.consume_digits();
+
+            if matches!(self.peek_char(), Some('+' | '-')) {
+                self.advance();
+            }
+            if !self.peek_char().is_some_and(ScannerBuilder::is_digit) {
+                self.consume_while(ScannerBuilder::is_identifier_char);
+                return self.add_error(ScannerError::MissingExponent);
+            }
+            self.consume_while(ScannerBuilder::is_digit_or_separator);
         }
 
+        let digits = self.current_digits();
         if is_float {
-            self.add_literal_token(
-                TokenKind::Literal,
-                LiteralValue::Float(self.current_slice().parse::<f64>().unwrap()),
-            )
+            // Rust's float grammar is a superset of ours, so this can't fail.
+            let value = digits.parse::<f64>().unwrap();
+            self.add_literal_token(TokenKind::Literal, LiteralValue::Float(value))
         } else {
-            self.add_literal_token(
-                TokenKind::Literal,
-                LiteralValue::Integer(self.current_slice().parse::<isize>().unwrap()),
-            )
+            match digits.parse::<isize>() {
+                Ok(value) => {
+                    self.add_literal_token(TokenKind::Literal, LiteralValue::Integer(value))
+                }
+                Err(_) => self.add_error(ScannerError::IntegerOverflow),
+            }
         }
     }
 
+    fn radix_integer(&mut self, radix: u32) {
+        // Consume anything that could belong to the literal so that a stray
+        // digit like the `2` in `0b102` is reported instead of split off.
+        self.consume_while(ScannerBuilder::is_identifier_char);
+
+        let digits = &self.current_digits()[2..];
+
+        if digits.chars().any(|c| !c.is_digit(radix)) {
+            return self.add_error(ScannerError::InvalidDigit(radix));
+        }
+        if digits.is_empty() {
+            return self.add_error(ScannerError::MissingDigits);
+        }
+
+        match isize::from_str_radix(digits, radix) {
+            Ok(value) => self.add_literal_token(TokenKind::Literal, LiteralValue::Integer(value)),
+            Err(_) => self.add_error(ScannerError::IntegerOverflow),
+        }
+    }
+
+    fn leading_dot(&mut self) {
+        self.consume_while(ScannerBuilder::is_identifier_char);
+        self.add_error(ScannerError::LeadingDot);
+    }
+
     fn identifier(&mut self) {
-        while self.peek_char().map_or(false, |c| {
-            ScannerBuilder::is_identifier_char(c) || ScannerBuilder::is_digit(c)
-        }) {
+        while self
+            .peek_char()
+            .is_some_and(|c| ScannerBuilder::is_identifier_char(c) || ScannerBuilder::is_digit(c))
+        {
             self.advance();
         }
 
@@ -291,8 +422,12 @@ impl<'source> ScannerBuilder<'source> {
     }
 }
 
+#[cfg(test)]
 mod test {
-    use crate::compiler::{scanner::ScannerError, token::TokenKind};
+    use crate::compiler::{
+        scanner::ScannerError,
+        token::{LiteralValue, TokenKind},
+    };
 
     use super::{Scanner, ScannerResult};
 
@@ -316,9 +451,243 @@ mod test {
             TokenKind::Literal,
         ] {
             assert_kind(scanner.peek(), kind);
-            assert_kind(scanner.next(), kind);
+            assert_kind(scanner.next_token(), kind);
+        }
+
+        assert_eof(scanner.next_token());
+        assert_eof(scanner.next_token());
+    }
+
+    #[test]
+    fn lines_and_columns() {
+        let positions: Vec<_> = Scanner::new("var é = 1;\n  é + \"a\"")
+            .map(|token| {
+                let token = token.unwrap();
+                (token.line, token.column)
+            })
+            .collect();
+        assert_eq!(
+            positions,
+            [
+                (1, 1),
+                (1, 5),
+                (1, 7),
+                (1, 9),
+                (1, 10),
+                (2, 3),
+                (2, 5),
+                (2, 7)
+            ]
+        );
+    }
+
+    #[test]
+    fn match_arms() {
+        let mut scanner = Scanner::new("match x { _ => 1 }");
+
+        for kind in [
+            TokenKind::Match,
+            TokenKind::Identifier,
+            TokenKind::LeftCurly,
+            TokenKind::Identifier,
+            TokenKind::FatArrow,
+            TokenKind::Literal,
+            TokenKind::RightCurly,
+        ] {
+            assert_kind(scanner.next_token(), kind);
+        }
+        assert_eof(scanner.next_token());
+    }
+
+    #[test]
+    fn lookahead() {
+        let mut scanner = Scanner::new("a = (b)");
+
+        assert_kind(scanner.peek_nth(3), TokenKind::Identifier);
+        assert_kind(scanner.peek_nth(1), TokenKind::Equal);
+        assert_eof(scanner.peek_nth(5));
+
+        assert_eq!(scanner.next_token().unwrap().lexeme, "a");
+        assert_kind(scanner.peek_nth(0), TokenKind::Equal);
+        assert_eq!(scanner.peek_nth(2).unwrap().lexeme, "b");
+        assert_eq!(scanner.count(), 4);
+    }
+
+    #[test]
+    fn scans_lazily() {
+        let source = "1 + ".repeat(10_000) + "1";
+        let mut scanner = Scanner::new(source.as_str());
+
+        assert_kind(scanner.peek_nth(2), TokenKind::Literal);
+        assert_eq!(scanner.builder.tokens.len(), 3);
+        assert!(scanner.builder.current < 10);
+    }
+
+    #[test]
+    fn unicode_identifiers() {
+        let mut scanner = Scanner::new("var переменная = 变量 + λ_1 + _ñ");
+
+        for lexeme in ["var", "переменная", "=", "变量", "+", "λ_1", "+", "_ñ"] {
+            assert_eq!(scanner.next_token().unwrap().lexeme, lexeme);
         }
+        assert_eof(scanner.next_token());
+    }
+
+    #[test]
+    fn unicode_strings() {
+        let mut scanner = Scanner::new("\"héllo 🌍\" + \"日本語\"");
+
+        let token = scanner.next_token().unwrap();
+        assert_eq!(token.lexeme, "\"héllo 🌍\"");
+        assert_eq!(token.literal, LiteralValue::String("héllo 🌍".into()));
+        assert_kind(scanner.next_token(), TokenKind::Plus);
+        assert_eq!(
+            scanner.next_token().unwrap().literal,
+            LiteralValue::String("日本語".into())
+        );
+        assert_eof(scanner.next_token());
+    }
+
+    #[test]
+    fn unexpected_multibyte_characters() {
+        let mut scanner = Scanner::new("a 🌍 b ¬");
+
+        assert_eq!(scanner.next_token().unwrap().lexeme, "a");
+        assert_eq!(scanner.next_token(), Err(ScannerError::UnexpectedToken));
+        assert_eq!(scanner.next_token().unwrap().lexeme, "b");
+        assert_eq!(scanner.next_token(), Err(ScannerError::UnexpectedToken));
+        assert_eof(scanner.next_token());
+    }
+
+    #[test]
+    fn identifiers_do_not_start_with_digits_or_marks() {
+        let mut scanner = Scanner::new("1a \u{0301}");
+
+        assert_kind(scanner.next_token(), TokenKind::Literal);
+        assert_eq!(scanner.next_token().unwrap().lexeme, "a");
+        assert_eq!(scanner.next_token(), Err(ScannerError::UnexpectedToken));
+    }
 
-        assert_eof(scanner.next());
+    fn assert_literal(source: &str, literal: LiteralValue) {
+        let mut scanner = Scanner::new(source);
+        assert_eq!(scanner.next_token().unwrap().literal, literal, "{source}");
+        assert_eof(scanner.next_token());
+    }
+
+    #[test]
+    fn numbers() {
+        assert_literal("42", LiteralValue::Integer(42));
+        assert_literal("1_000_000", LiteralValue::Integer(1_000_000));
+        assert_literal("0xFF", LiteralValue::Integer(0xFF));
+        assert_literal("0xdead_beef", LiteralValue::Integer(0xdead_beef));
+        assert_literal("0o17", LiteralValue::Integer(0o17));
+        assert_literal("0b1010_1010", LiteralValue::Integer(0b1010_1010));
+        assert_literal("3.25", LiteralValue::Float(3.25));
+        assert_literal("1_000.000_1", LiteralValue::Float(1_000.000_1));
+        assert_literal("1e-9", LiteralValue::Float(1e-9));
+        assert_literal("2.5E+3", LiteralValue::Float(2.5e3));
+        assert_literal("6e2", LiteralValue::Float(600.0));
+    }
+
+    #[test]
+    fn numbers_next_to_dots() {
+        let mut scanner = Scanner::new("1..5");
+
+        assert_eq!(
+            scanner.next_token().unwrap().literal,
+            LiteralValue::Integer(1)
+        );
+        assert_kind(scanner.next_token(), TokenKind::DotDot);
+        assert_eq!(
+            scanner.next_token().unwrap().literal,
+            LiteralValue::Integer(5)
+        );
+        assert_eof(scanner.next_token());
+    }
+
+    #[test]
+    fn dots_after_operands() {
+        let mut scanner = Scanner::new("t.0 (t).1 [t].2");
+
+        for kind in [TokenKind::Identifier, TokenKind::Dot] {
+            assert_kind(scanner.next_token(), kind);
+        }
+        assert_eq!(
+            scanner.next_token().unwrap().literal,
+            LiteralValue::Integer(0)
+        );
+        for kind in [
+            TokenKind::LeftParen,
+            TokenKind::Identifier,
+            TokenKind::RightParen,
+            TokenKind::Dot,
+            TokenKind::Literal,
+            TokenKind::LeftBrace,
+            TokenKind::Identifier,
+            TokenKind::RightBrace,
+            TokenKind::Dot,
+            TokenKind::Literal,
+        ] {
+            assert_kind(scanner.next_token(), kind);
+        }
+        assert_eof(scanner.next_token());
+
+        let mut scanner = Scanner::new("= .5");
+        assert_kind(scanner.next_token(), TokenKind::Equal);
+        assert_eq!(scanner.next_token().unwrap_err(), ScannerError::LeadingDot);
+    }
+
+    #[test]
+    fn malformed_numbers() {
+        for (source, error) in [
+            (".5", ScannerError::LeadingDot),
+            ("0x", ScannerError::MissingDigits),
+            ("0b_", ScannerError::MissingDigits),
+            ("0b102", ScannerError::InvalidDigit(2)),
+            ("0o8", ScannerError::InvalidDigit(8)),
+            ("0xFG", ScannerError::InvalidDigit(16)),
+            ("1e", ScannerError::MissingExponent),
+            ("1e+x", ScannerError::MissingExponent),
+            ("99999999999999999999", ScannerError::IntegerOverflow),
+            ("0xFFFF_FFFF_FFFF_FFFF_F", ScannerError::IntegerOverflow),
+        ] {
+            let mut scanner = Scanner::new(source);
+            assert_eq!(scanner.next_token(), Err(error), "{source}");
+            assert_eof(scanner.next_token());
+        }
+    }
+
+    #[test]
+    fn operators() {
+        let mut scanner =
+            Scanner::new("+ += - -= * *= ** / /= // comment\n % %= ~/ ~ & | ^ << <= < >> >= >");
+
+        for kind in [
+            TokenKind::Plus,
+            TokenKind::PlusEqual,
+            TokenKind::Minus,
+            TokenKind::MinusEqual,
+            TokenKind::Star,
+            TokenKind::StarEqual,
+            TokenKind::StarStar,
+            TokenKind::Slash,
+            TokenKind::SlashEqual,
+            TokenKind::Percent,
+            TokenKind::PercentEqual,
+            TokenKind::TildeSlash,
+            TokenKind::Tilde,
+            TokenKind::Ampersand,
+            TokenKind::Pipe,
+            TokenKind::Caret,
+            TokenKind::LessLess,
+            TokenKind::LessEqual,
+            TokenKind::Less,
+            TokenKind::GreaterGreater,
+            TokenKind::GreaterEqual,
+            TokenKind::Greater,
+        ] {
+            assert_kind(scanner.next_token(), kind);
+        }
+        assert_eof(scanner.next_token());
     }
 }
@@ -10,22 +10,39 @@ pub enum TokenKind {
     LeftCurly,
     RightCurly,
     Comma,
+    Colon,
+    Semicolon,
+    // One or two characters,
     Minus,
+    MinusEqual,
     Plus,
+    PlusEqual,
     Star,
-    Semicolon,
+    StarEqual,
+    StarStar,
     Slash,
-    // One or two characters,
+    SlashEqual,
+    Percent,
+    PercentEqual,
+    TildeSlash,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
     Dot,
     DotDot,
     Bang,
+    Question,
     BangEqual,
     Equal,
     EqualEqual,
+    FatArrow,
     Greater,
     GreaterEqual,
+    GreaterGreater,
     Less,
     LessEqual,
+    LessLess,
     // Literals
     Identifier,
     Literal,
@@ -33,8 +50,14 @@ pub enum TokenKind {
     And,
     Or,
     Class,
+    Enum,
     If,
     Else,
+    Match,
+    Try,
+    Catch,
+    Finally,
+    Throw,
     True,
     False,
     Fn,
@@ -63,5 +86,7 @@ pub struct Token<'source> {
     pub lexeme: &'source str,
     pub literal: LiteralValue,
     pub line: usize,
+    /// Counted in characters from 1.
+    pub column: usize,
     pub id: usize,
 }
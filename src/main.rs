@@ -1,23 +1,28 @@
 use lof_lang::{
-    compiler::{
-        scanner::{Scanner, ScannerError},
-        token::TokenKind,
-    },
-    virtual_machine::{chunk::Chunk, op_code::OpCode, vm::VM},
+    compiler::{compiler::compile_with_warnings, scanner::Scanner},
+    virtual_machine::vm::VM,
 };
 
 fn main() {
     let source = "1 + 2 * 3";
 
-    let mut scanner = Scanner::new(source);
-
-    loop {
-        let token = scanner.next();
-
+    for token in Scanner::new(source) {
         dbg!(&token);
+    }
 
-        if let Err(ScannerError::Eof) = token {
-            break;
+    let (chunk, warnings) = match compile_with_warnings(source) {
+        Ok(compiled) => compiled,
+        Err(err) => {
+            eprintln!("error: {err:?}");
+            return;
         }
+    };
+    for warning in warnings {
+        eprintln!("warning: {warning}");
+    }
+
+    match VM::new(chunk).run() {
+        Ok(value) => println!("{value}"),
+        Err(err) => eprintln!("{err}"),
     }
 }
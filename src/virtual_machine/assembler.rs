@@ -0,0 +1,642 @@
+//! Reads bytecode back from the text `Chunk::disassemble` prints, which can
+//! also be written by hand:
+//!
+//! ```text
+//! =====================counter======================
+//! .const limit = 3
+//! .const 1
+//!    0     1  NIL
+//! loop:
+//!          2  GET_LOCAL 1
+//!          |  CONSTANT limit
+//!             LESS
+//!             JUMP_IF_FALSE end   ; leaves the condition on the stack
+//! ```
+//!
+//! A line of `=` around a name starts a chunk and names it. `.const` lines
+//! add constants in order, optionally with a name, and `.const fn f(a, b?,
+//! ..rest) {` starts a function whose chunk ends at the matching `}`. In a
+//! function header, `?` marks parameters with defaults, `_` destructured
+//! ones and `..` a variadic last one.
+//!
+//! Instructions may start with their offset, which is checked, and their
+//! source line, or `line:column`, where `|` or nothing means the place of
+//! the instruction before. Jumps can go to a label and constant operands
+//! can be names. What the disassembler adds after the operands, `-> target`
+//! and `[index]: constant`, is ignored like `;` comments.
+
+use std::{collections::HashMap, rc::Rc};
+
+use thiserror::Error;
+
+use super::{
+    chunk::Chunk,
+    function::{Function, Parameter},
+    op_code::OpCode,
+    value::{Value, Variant},
+};
+
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("[line {line}] {message}")]
+pub struct AssembleError {
+    pub line: usize,
+    pub message: String,
+}
+
+/// Builds a chunk from its textual form.
+pub fn assemble(source: &str) -> Result<Chunk, AssembleError> {
+    let mut assembler = Assembler {
+        chunks: vec![ChunkState::new(None)],
+    };
+    for (i, line) in source.lines().enumerate() {
+        assembler.line(line).map_err(|message| AssembleError {
+            line: i + 1,
+            message,
+        })?;
+    }
+
+    let line = source.lines().count();
+    let error = |message: String| AssembleError { line, message };
+    if assembler.chunks.len() > 1 {
+        return Err(error("expected '}' to end the function".into()));
+    }
+    let state = assembler.chunks.pop().expect("the script's chunk is there");
+    state.finish().map_err(error)
+}
+
+struct Assembler {
+    /// The chunk being assembled, after the chunks of the functions it's
+    /// in.
+    chunks: Vec<ChunkState>,
+}
+
+struct ChunkState {
+    chunk: Chunk,
+    /// The function the chunk belongs to, unless it's the script's, and the
+    /// name of its constant.
+    function: Option<(Function, Option<String>)>,
+    constants: HashMap<String, usize>,
+    labels: HashMap<String, usize>,
+    /// Jumps to labels that are filled in once the chunk is done.
+    jumps: Vec<Jump>,
+    line: usize,
+    column: usize,
+}
+
+struct Jump {
+    op: OpCode,
+    /// Where the jump's two bytes are.
+    at: usize,
+    /// Where the instruction after the jump starts.
+    next: usize,
+    label: String,
+}
+
+impl ChunkState {
+    fn new(function: Option<(Function, Option<String>)>) -> Self {
+        Self {
+            chunk: Chunk::new(),
+            function,
+            constants: HashMap::new(),
+            labels: HashMap::new(),
+            jumps: Vec::new(),
+            line: 1,
+            column: 0,
+        }
+    }
+
+    fn finish(mut self) -> Result<Chunk, String> {
+        for jump in &self.jumps {
+            let Some(&target) = self.labels.get(&jump.label) else {
+                return Err(format!("undefined label '{}'", jump.label));
+            };
+            let distance = match jump.op {
+                OpCode::Loop => jump.next.checked_sub(target),
+                _ => target.checked_sub(jump.next),
+            }
+            .ok_or_else(|| format!("{} can't jump to '{}'", jump.op, jump.label))?;
+            let distance = u16::try_from(distance)
+                .map_err(|_| format!("'{}' is too far away to jump to", jump.label))?;
+            self.chunk.code[jump.at..jump.at + 2].copy_from_slice(&distance.to_be_bytes());
+        }
+        self.chunk.share_constants();
+        Ok(self.chunk)
+    }
+}
+
+impl Assembler {
+    fn state(&mut self) -> &mut ChunkState {
+        self.chunks.last_mut().expect("there is a chunk")
+    }
+
+    fn line(&mut self, line: &str) -> Result<(), String> {
+        let line = match line.find(';') {
+            Some(comment) if !line.trim_start().starts_with(".const") => &line[..comment],
+            _ => line,
+        };
+        let line = line.trim();
+
+        if line.is_empty() {
+            Ok(())
+        } else if line.starts_with('=') {
+            let name = line.trim_matches('=');
+            self.state().chunk.name = (!name.is_empty()).then(|| name.into());
+            Ok(())
+        } else if let Some(constant) = line.strip_prefix(".const") {
+            self.constant(constant)
+        } else if line == "}" {
+            self.end_function()
+        } else if let Some(label) = line
+            .strip_suffix(':')
+            .filter(|label| !label.contains(char::is_whitespace))
+        {
+            let state = self.state();
+            let offset = state.chunk.code.len();
+            if state.labels.insert(label.into(), offset).is_some() {
+                return Err(format!("label '{label}' is defined twice"));
+            }
+            Ok(())
+        } else {
+            self.instruction(line)
+        }
+    }
+
+    fn constant(&mut self, text: &str) -> Result<(), String> {
+        let mut parser = Parser::new(text);
+        let name = parser.constant_name();
+
+        if parser.keyword("fn") {
+            let function = parser.function_header()?;
+            self.chunks.push(ChunkState::new(Some((function, name))));
+            return Ok(());
+        }
+
+        let value = parser.value()?;
+        parser.end()?;
+        self.add_constant(value, name)
+    }
+
+    fn add_constant(&mut self, value: Value, name: Option<String>) -> Result<(), String> {
+        // Constants keep the indices they were given, even if they repeat.
+        let state = self.state();
+        state.chunk.constants.push(value);
+        if let Some(name) = name {
+            let index = state.chunk.constants.len() - 1;
+            if state.constants.insert(name.clone(), index).is_some() {
+                return Err(format!("constant '{name}' is defined twice"));
+            }
+        }
+        Ok(())
+    }
+
+    fn end_function(&mut self) -> Result<(), String> {
+        if self.chunks.len() == 1 {
+            return Err("'}' outside of a function".into());
+        }
+        let mut state = self.chunks.pop().expect("the function's chunk is there");
+        let (function, name) = state.function.take().expect("the chunk is a function's");
+        let function = Function {
+            chunk: Rc::new(state.finish()?),
+            ..function
+        };
+        self.add_constant(Value::Function(Rc::new(function)), name)
+    }
+
+    fn instruction(&mut self, text: &str) -> Result<(), String> {
+        let mut tokens = text
+            .split_whitespace()
+            .take_while(|token| !token.starts_with('[') && !token.starts_with("->"))
+            .peekable();
+
+        let mut prefix = Vec::new();
+        while let Some(token) = tokens.next_if(|token| *token == "|" || position(token).is_some()) {
+            prefix.push(token);
+        }
+        let (offset, line) = match prefix[..] {
+            [] => (None, None),
+            [line] => (None, Some(line)),
+            [offset, line] => (Some(offset), Some(line)),
+            _ => return Err(format!("expected an instruction, found '{text}'")),
+        };
+
+        let state = self.state();
+        let start = state.chunk.code.len();
+        if let Some(offset) = offset {
+            if offset.parse::<usize>() != Ok(start) {
+                return Err(format!(
+                    "the instruction is at offset {start}, not {offset}"
+                ));
+            }
+        }
+        if let Some((line, column)) = line.and_then(position) {
+            (state.line, state.column) = (line, column);
+        }
+
+        let name = tokens.next().ok_or("expected an instruction")?;
+        let op = opcode(name).ok_or_else(|| format!("unknown instruction '{name}'"))?;
+        let next = start + 1 + op.num_operands();
+
+        let mut operands = Vec::new();
+        while operands.len() < op.num_operands() {
+            let token = tokens.next().ok_or_else(|| operand_count(op))?;
+            if let Ok(byte) = token.parse::<u8>() {
+                operands.push(byte);
+            } else if op.jump_operand() == Some(operands.len()) {
+                state.jumps.push(Jump {
+                    op,
+                    at: start + 1 + operands.len(),
+                    next,
+                    label: token.into(),
+                });
+                operands.extend([0, 0]);
+            } else if operands.is_empty() && op.has_constant_operand() {
+                let &index = state
+                    .constants
+                    .get(token)
+                    .ok_or_else(|| format!("undefined constant '{token}'"))?;
+                match (op.constant_width(), op.long_form()) {
+                    (3, _) => {
+                        let [_, high, middle, low] = (index as u32).to_be_bytes();
+                        operands.extend([high, middle, low]);
+                    }
+                    (_, long) => operands.push(
+                        u8::try_from(index)
+                            .map_err(|_| format!("'{token}' needs {}", long.unwrap_or(op)))?,
+                    ),
+                }
+            } else {
+                return Err(format!("invalid operand '{token}' for {op}"));
+            }
+        }
+        if tokens.next().is_some() {
+            return Err(operand_count(op));
+        }
+
+        let (line, column) = (state.line, state.column);
+        state.chunk.write_op_code(op, line, column);
+        for operand in operands {
+            state.chunk.write_operand(operand, line, column);
+        }
+        Ok(())
+    }
+}
+
+/// A source line, with the column after a `:` if it's known.
+fn position(token: &str) -> Option<(usize, usize)> {
+    match token.split_once(':') {
+        Some((line, column)) => Some((line.parse().ok()?, column.parse().ok()?)),
+        None => Some((token.parse().ok()?, 0)),
+    }
+}
+
+fn operand_count(op: OpCode) -> String {
+    match op.num_operands() {
+        1 => format!("{op} takes 1 operand"),
+        n => format!("{op} takes {n} operands"),
+    }
+}
+
+fn opcode(name: &str) -> Option<OpCode> {
+    (0..=u8::MAX)
+        .filter_map(|byte| OpCode::try_from(byte).ok())
+        .find(|op| op.to_string() == name)
+}
+
+/// Reads constants and function headers.
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        Self { rest: text }
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        match self.rest.strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.eat(c) {
+            true => Ok(()),
+            false => Err(format!("expected '{c}'")),
+        }
+    }
+
+    fn end(&mut self) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.rest.strip_prefix(';') {
+            _ if self.rest.is_empty() => Ok(()),
+            Some(_) => Ok(()),
+            None => Err(format!("unexpected '{}'", self.rest)),
+        }
+    }
+
+    /// Everything up to the next space, comma or bracket.
+    fn word(&mut self) -> &'a str {
+        self.skip_whitespace();
+        let end = self
+            .rest
+            .find(|c: char| c.is_whitespace() || ",(){}=".contains(c))
+            .unwrap_or(self.rest.len());
+        let (word, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        word
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let before = self.rest;
+        if self.word() == keyword {
+            return true;
+        }
+        self.rest = before;
+        false
+    }
+
+    /// The name in `.const name = value`, if there is one.
+    fn constant_name(&mut self) -> Option<String> {
+        let before = self.rest;
+        let name = self.word();
+        let is_name = name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+        if is_name && self.eat('=') {
+            return Some(name.into());
+        }
+        self.rest = before;
+        None
+    }
+
+    /// `name(params) {`, with a chunk to follow.
+    fn function_header(&mut self) -> Result<Function, String> {
+        let name = self.word();
+        if name.is_empty() {
+            return Err("expected the function's name".into());
+        }
+        self.expect('(')?;
+
+        let mut params = Vec::new();
+        let mut variadic = false;
+        while !self.eat(')') {
+            if !params.is_empty() {
+                self.expect(',')?;
+            }
+            if variadic {
+                return Err("only the last parameter can be variadic".into());
+            }
+            let mut param = self.word();
+            if let Some(rest) = param.strip_prefix("..") {
+                variadic = true;
+                param = rest;
+            }
+            let has_default = param.ends_with('?');
+            let name = param.trim_end_matches('?');
+            if name.is_empty() {
+                return Err("expected a parameter".into());
+            }
+            params.push(Parameter {
+                name: (name != "_").then(|| name.into()),
+                has_default,
+            });
+        }
+        self.expect('{')?;
+        self.end()?;
+
+        Ok(Function {
+            name: name.into(),
+            chunk: Rc::new(Chunk::new()),
+            params: params.into(),
+            variadic,
+            defaults: Vec::new(),
+        })
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        if self.rest.starts_with('"') {
+            return self.string().map(|s| Value::String(s.into()));
+        }
+        if self.eat('(') {
+            let (elements, trailing_comma) = self.elements()?;
+            return match elements.len() {
+                1 if !trailing_comma => Err("one-element tuples are written '(value,)'".into()),
+                _ => Ok(Value::new_tuple(elements)),
+            };
+        }
+
+        let word = self.word();
+        match word {
+            "nil" => return Ok(Value::Nil),
+            "true" => return Ok(Value::Bool(true)),
+            "false" => return Ok(Value::Bool(false)),
+            "" => return Err(format!("expected a constant, found '{}'", self.rest)),
+            _ => {}
+        }
+        if let Ok(i) = word.parse() {
+            return Ok(Value::Integer(i));
+        }
+        if let Ok(f) = word.parse() {
+            return Ok(Value::Float(f));
+        }
+        self.variant(word)
+    }
+
+    /// `Enum.Variant/arity`, followed by its payload if it has one.
+    fn variant(&mut self, word: &str) -> Result<Value, String> {
+        let invalid = || format!("invalid constant '{word}'");
+        let (path, arity) = word.split_once('/').ok_or_else(invalid)?;
+        let (enum_name, name) = path.split_once('.').ok_or_else(invalid)?;
+        let variant = Variant {
+            enum_name: enum_name.into(),
+            name: name.into(),
+            arity: arity.parse().map_err(|_| invalid())?,
+        };
+
+        let payload = match self.eat('(') {
+            true => self.elements()?.0,
+            false => Vec::new(),
+        };
+        Ok(Value::new_variant(Rc::new(variant), payload))
+    }
+
+    /// The values up to a `)`, and whether a comma came last.
+    fn elements(&mut self) -> Result<(Vec<Value>, bool), String> {
+        let mut elements = Vec::new();
+        let mut trailing_comma = false;
+        while !self.eat(')') {
+            if !elements.is_empty() && !trailing_comma {
+                return Err("expected ',' or ')'".into());
+            }
+            elements.push(self.value()?);
+            trailing_comma = self.eat(',');
+        }
+        Ok((elements, trailing_comma))
+    }
+
+    /// A string with the escapes Rust's `{:?}` writes.
+    fn string(&mut self) -> Result<String, String> {
+        let mut chars = self.rest.char_indices().skip(1);
+        let mut string = String::new();
+        while let Some((i, c)) = chars.next() {
+            let c = match c {
+                '"' => {
+                    self.rest = &self.rest[i + 1..];
+                    return Ok(string);
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('0') => '\0',
+                    Some(c @ ('\\' | '"' | '\'')) => c,
+                    Some('u') => {
+                        let hex: String = chars
+                            .by_ref()
+                            .map(|(_, c)| c)
+                            .skip_while(|&c| c == '{')
+                            .take_while(|&c| c != '}')
+                            .collect();
+                        u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("invalid escape '\\u{{{hex}}}'"))?
+                    }
+                    _ => return Err("invalid escape in a string".into()),
+                },
+                c => c,
+            };
+            string.push(c);
+        }
+        Err("unterminated string".into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        compiler::compiler::compile,
+        virtual_machine::{chunk::Chunk, op_code::OpCode, value::Value, vm::VM},
+    };
+
+    use super::assemble;
+
+    #[test]
+    fn round_trips_the_disassembler() {
+        for source in [
+            "1 + 2 * 3",
+            "var greeting = \"tab\t, back\\slash; é and newline\n\"; greeting + \"!\"",
+            "[1.0, -0.0, 1e300, 0.1, (1,), (), (1, (2, nil))]",
+            "fn f(a, (b, c), d = 1, ..rest) { a + b + c + d } f(1, (2, 3), 4, 5, 6) + f(2, (3, 4), d: 1)",
+            "var total = 0; for x in [1, 2, 3] { total += x; } total",
+            "try { throw 1; } catch e { e } finally { 2 }",
+            "enum E { A, B(x) } match E.B(1) { E.A => 0, E.B(x) if x > 0 => x, _ => -1 }",
+            "fn half(n) { if n % 2 == 0 { Ok(n / 2) } else { Err(n) } }
+             fn quarter(n) { Ok(half(half(n)?)?) } quarter(8)",
+        ] {
+            let chunk = compile(source).unwrap();
+            let text = chunk.disassemble().unwrap();
+            let assembled = assemble(&text).unwrap_or_else(|err| panic!("{err}\n{text}"));
+            assert_eq!(assembled, chunk, "{text}");
+            assert_eq!(assembled.disassemble().unwrap(), text);
+        }
+
+        // Past 256 constants, where the `_LONG` instructions come in.
+        let globals: String = (0..150).map(|i| format!("var v{i} = {i}; ")).collect();
+        let chunk = compile(&format!("{globals}v149 += 1; v149")).unwrap();
+        let text = chunk.disassemble().unwrap();
+        assert!(text.contains("SET_GLOBAL_LONG"), "{text}");
+        assert_eq!(assemble(&text), Ok(chunk));
+
+        let mut named = Chunk::new_named("(a + b) * (c - d)");
+        named.write_operation(OpCode::Nil, [], 1, 0);
+        named.write_operation(OpCode::Return, [], 1, 0);
+        assert_eq!(assemble(&named.disassemble().unwrap()), Ok(named));
+
+        let mut list = Chunk::new();
+        list.add_constant(Value::new_list(Vec::new()));
+        assert!(list.disassemble().is_err());
+    }
+
+    #[test]
+    fn labels_and_named_constants() {
+        let chunk = assemble(
+            "
+            ====================counter=====================
+            .const limit = 3
+            .const one = 1
+            .const 0          ; the counter's start
+               0     1  CONSTANT 2
+            loop:
+                     2  GET_LOCAL 1
+                     |  CONSTANT limit
+                        LESS
+                        JUMP_IF_FALSE end
+                        POP
+                   3:5  GET_LOCAL 1
+                        CONSTANT one
+                        ADD
+                        SET_LOCAL 1
+                        POP
+                        LOOP loop
+            end:
+                     4  POP
+                        RETURN  ; the counter
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(chunk.name.as_deref(), Some("counter"));
+        assert_eq!(chunk.line_for_offset(4), 2);
+        assert_eq!(chunk.line_for_offset(23), 4);
+        assert_eq!(
+            (chunk.line_for_offset(11), chunk.column_for_offset(11)),
+            (3, 5)
+        );
+        assert_eq!(chunk.column_for_offset(4), 0);
+        chunk.verify().unwrap();
+        assert_eq!(VM::new(chunk).run(), Ok(Value::Integer(3)));
+    }
+
+    #[test]
+    fn reports_errors() {
+        for (source, line, message) in [
+            ("NOPE", 1, "unknown instruction 'NOPE'"),
+            ("NIL\nJUMP nowhere", 2, "undefined label 'nowhere'"),
+            ("CONSTANT x", 1, "undefined constant 'x'"),
+            ("ADD 1", 1, "ADD takes 0 operands"),
+            ("GET_LOCAL", 1, "GET_LOCAL takes 1 operand"),
+            ("3 1 NIL", 1, "the instruction is at offset 0, not 3"),
+            ("top:\nLOOP top\ntop:", 3, "label 'top' is defined twice"),
+            (
+                "NIL\nJUMP back\nback:\nLOOP forward\nNIL\nforward:",
+                6,
+                "LOOP can't jump to 'forward'",
+            ),
+            (".const \"open", 1, "unterminated string"),
+            (".const (1)", 1, "one-element tuples are written '(value,)'"),
+            (
+                ".const fn f(a) {\nRETURN",
+                2,
+                "expected '}' to end the function",
+            ),
+            ("}", 1, "'}' outside of a function"),
+        ] {
+            let error = assemble(source).unwrap_err();
+            assert_eq!(
+                (error.line, error.message.as_str()),
+                (line, message),
+                "{source}"
+            );
+        }
+    }
+}
@@ -0,0 +1,463 @@
+//! The `.lofc` format for saving compiled chunks and loading them later.
+//!
+//! A file starts with a header: the magic bytes `LOFC`, the format version
+//! and the length and CRC-32 of the rest of the file, all little-endian.
+//! After it comes the script's chunk, which holds the chunks of the
+//! functions it declares among its constants.
+
+use std::{
+    io::{Read, Write},
+    rc::Rc,
+};
+
+use thiserror::Error;
+
+use super::{
+    chunk::{Chunk, LineRun, LineTable},
+    function::{Function, Parameter},
+    value::{Value, Variant},
+    verifier::VerifyError,
+};
+
+pub const MAGIC: [u8; 4] = *b"LOFC";
+
+/// Bumped whenever the layout or the meaning of the bytecode changes, since
+/// files from other versions can't be run.
+pub const FORMAT_VERSION: u16 = 2;
+
+/// How deeply constants can nest, so a malicious file can't overflow the
+/// stack while it's loaded.
+const MAX_DEPTH: usize = 64;
+
+const NIL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const INTEGER: u8 = 3;
+const FLOAT: u8 = 4;
+const STRING: u8 = 5;
+const TUPLE: u8 = 6;
+const ENUM: u8 = 7;
+const FUNCTION: u8 = 8;
+
+#[derive(Error, Debug)]
+pub enum BinaryError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("not a .lofc file")]
+    NotBytecode,
+    #[error("the file is format version {0}, but only version {FORMAT_VERSION} is supported")]
+    UnsupportedVersion(u16),
+    #[error("the file is corrupted")]
+    ChecksumMismatch,
+    #[error("malformed bytecode: {0}")]
+    Malformed(&'static str),
+    #[error(transparent)]
+    Invalid(#[from] VerifyError),
+    #[error("{0} can't be saved")]
+    Unsaveable(String),
+}
+
+impl Chunk {
+    /// Saves the chunk in the `.lofc` format.
+    pub fn save(&self, mut writer: impl Write) -> Result<(), BinaryError> {
+        let mut body = Vec::new();
+        write_chunk(&mut body, self)?;
+        let len =
+            u32::try_from(body.len()).map_err(|_| BinaryError::Unsaveable("the chunk".into()))?;
+
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(&crc32(&body).to_le_bytes())?;
+        writer.write_all(&body)?;
+        Ok(())
+    }
+
+    /// Loads a chunk saved with [`Chunk::save`], and verifies it since the
+    /// file could have been made by anything.
+    pub fn load(mut reader: impl Read) -> Result<Chunk, BinaryError> {
+        let mut header = [0; 14];
+        reader.read_exact(&mut header).map_err(truncated)?;
+        let mut header = Reader::new(&header);
+
+        if header.bytes(4)? != MAGIC {
+            return Err(BinaryError::NotBytecode);
+        }
+        let version = header.u16()?;
+        if version != FORMAT_VERSION {
+            return Err(BinaryError::UnsupportedVersion(version));
+        }
+        let len = header.u32()? as usize;
+        let checksum = header.u32()?;
+
+        let mut body = Vec::new();
+        reader.take(len as u64).read_to_end(&mut body)?;
+        if body.len() != len {
+            return Err(BinaryError::Malformed("unexpected end of file"));
+        }
+        if crc32(&body) != checksum {
+            return Err(BinaryError::ChecksumMismatch);
+        }
+
+        let mut body = Reader::new(&body);
+        let chunk = body.chunk(0)?;
+        if !body.bytes.is_empty() {
+            return Err(BinaryError::Malformed("trailing bytes"));
+        }
+        chunk.verify()?;
+        Ok(chunk)
+    }
+}
+
+fn truncated(error: std::io::Error) -> BinaryError {
+    match error.kind() {
+        std::io::ErrorKind::UnexpectedEof => BinaryError::NotBytecode,
+        _ => error.into(),
+    }
+}
+
+fn write_len(out: &mut Vec<u8>, len: usize) -> Result<(), BinaryError> {
+    let len = u32::try_from(len).map_err(|_| BinaryError::Unsaveable("the chunk".into()))?;
+    out.extend(len.to_le_bytes());
+    Ok(())
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) -> Result<(), BinaryError> {
+    write_len(out, s.len())?;
+    out.extend(s.as_bytes());
+    Ok(())
+}
+
+fn write_optional_str(out: &mut Vec<u8>, s: Option<&str>) -> Result<(), BinaryError> {
+    match s {
+        Some(s) => {
+            out.push(1);
+            write_str(out, s)
+        }
+        None => {
+            out.push(0);
+            Ok(())
+        }
+    }
+}
+
+fn write_chunk(out: &mut Vec<u8>, chunk: &Chunk) -> Result<(), BinaryError> {
+    write_optional_str(out, chunk.name.as_deref())?;
+
+    write_len(out, chunk.code.len())?;
+    out.extend(&chunk.code);
+
+    let runs: Vec<_> = chunk.lines.runs().collect();
+    write_len(out, runs.len())?;
+    for run in runs {
+        write_len(out, run.start)?;
+        write_len(out, run.line)?;
+        write_len(out, run.column)?;
+    }
+
+    write_len(out, chunk.constants.len())?;
+    for constant in &chunk.constants {
+        write_value(out, constant)?;
+    }
+    Ok(())
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) -> Result<(), BinaryError> {
+    match value {
+        Value::Nil => out.push(NIL),
+        Value::Bool(false) => out.push(FALSE),
+        Value::Bool(true) => out.push(TRUE),
+        Value::Integer(i) => {
+            out.push(INTEGER);
+            out.extend(i.to_le_bytes());
+        }
+        Value::Float(f) => {
+            out.push(FLOAT);
+            out.extend(f.to_bits().to_le_bytes());
+        }
+        Value::String(s) => {
+            out.push(STRING);
+            write_str(out, s)?;
+        }
+        Value::Tuple(elements) => {
+            out.push(TUPLE);
+            write_len(out, elements.len())?;
+            for element in elements.iter() {
+                write_value(out, element)?;
+            }
+        }
+        Value::Enum(value) => {
+            out.push(ENUM);
+            write_str(out, &value.variant.enum_name)?;
+            write_str(out, &value.variant.name)?;
+            out.push(value.variant.arity);
+            write_len(out, value.payload.len())?;
+            for element in &value.payload {
+                write_value(out, element)?;
+            }
+        }
+        // Defaults are only filled in when the function is declared, so the
+        // prototypes in the constants don't have any.
+        Value::Function(function) if function.defaults.is_empty() => {
+            out.push(FUNCTION);
+            write_str(out, &function.name)?;
+            write_len(out, function.params.len())?;
+            for param in function.params.iter() {
+                write_optional_str(out, param.name.as_deref())?;
+                out.push(param.has_default as u8);
+            }
+            out.push(function.variadic as u8);
+            write_chunk(out, &function.chunk)?;
+        }
+        _ => return Err(BinaryError::Unsaveable(value.to_string())),
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], BinaryError> {
+        if n > self.bytes.len() {
+            return Err(BinaryError::Malformed("unexpected end of file"));
+        }
+        let (bytes, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], BinaryError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, BinaryError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, BinaryError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(BinaryError::Malformed("invalid flag")),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, BinaryError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, BinaryError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    /// A length, which can't be more than the bytes left since every item
+    /// takes up at least one.
+    fn len(&mut self) -> Result<usize, BinaryError> {
+        let len = self.u32()? as usize;
+        if len > self.bytes.len() {
+            return Err(BinaryError::Malformed("unexpected end of file"));
+        }
+        Ok(len)
+    }
+
+    fn str(&mut self) -> Result<Rc<str>, BinaryError> {
+        let len = self.len()?;
+        let bytes = self.bytes(len)?;
+        std::str::from_utf8(bytes)
+            .map(Into::into)
+            .map_err(|_| BinaryError::Malformed("invalid UTF-8 in a string"))
+    }
+
+    fn optional_str(&mut self) -> Result<Option<Rc<str>>, BinaryError> {
+        Ok(match self.bool()? {
+            true => Some(self.str()?),
+            false => None,
+        })
+    }
+
+    fn chunk(&mut self, depth: usize) -> Result<Chunk, BinaryError> {
+        let mut chunk = Chunk::new();
+        chunk.name = self.optional_str()?;
+
+        let len = self.len()?;
+        chunk.code = self.bytes(len)?.to_vec();
+
+        let mut runs = Vec::new();
+        for _ in 0..self.len()? {
+            let start = self.u32()? as usize;
+            let line = self.u32()? as usize;
+            let column = self.u32()? as usize;
+            runs.push(LineRun {
+                start,
+                line,
+                column,
+            });
+        }
+        chunk.lines = LineTable::from_runs(runs, chunk.code.len())
+            .ok_or(BinaryError::Malformed("invalid line table"))?;
+
+        for _ in 0..self.len()? {
+            chunk.constants.push(self.value(depth)?);
+        }
+        chunk.share_constants();
+        Ok(chunk)
+    }
+
+    fn values(&mut self, depth: usize) -> Result<Vec<Value>, BinaryError> {
+        (0..self.len()?).map(|_| self.value(depth)).collect()
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, BinaryError> {
+        if depth >= MAX_DEPTH {
+            return Err(BinaryError::Malformed("constants nested too deeply"));
+        }
+
+        Ok(match self.u8()? {
+            NIL => Value::Nil,
+            FALSE => Value::Bool(false),
+            TRUE => Value::Bool(true),
+            INTEGER => Value::Integer(i64::from_le_bytes(self.array()?)),
+            FLOAT => Value::Float(f64::from_bits(u64::from_le_bytes(self.array()?))),
+            STRING => Value::String(self.str()?),
+            TUPLE => Value::new_tuple(self.values(depth + 1)?),
+            ENUM => {
+                let variant = Variant {
+                    enum_name: self.str()?,
+                    name: self.str()?,
+                    arity: self.u8()?,
+                };
+                Value::new_variant(Rc::new(variant), self.values(depth + 1)?)
+            }
+            FUNCTION => {
+                let name = self.str()?;
+                let params = (0..self.len()?)
+                    .map(|_| {
+                        Ok(Parameter {
+                            name: self.optional_str()?,
+                            has_default: self.bool()?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, BinaryError>>()?;
+                let variadic = self.bool()?;
+                Value::Function(Rc::new(Function {
+                    name,
+                    chunk: Rc::new(self.chunk(depth + 1)?),
+                    params: params.into(),
+                    variadic,
+                    defaults: Vec::new(),
+                }))
+            }
+            _ => return Err(BinaryError::Malformed("unknown constant type")),
+        })
+    }
+}
+
+/// The CRC-32 used by zlib and PNG.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        compiler::compiler::compile,
+        virtual_machine::{chunk::Chunk, op_code::OpCode, value::Value, vm::VM},
+    };
+
+    use super::{crc32, BinaryError, FORMAT_VERSION};
+
+    const SOURCE: &str = "
+        enum Shape { Circle(r), Square(side) }
+        fn area(shape, scale = 1) {
+            match shape {
+                Shape.Circle(r) => 3 * r * r * scale,
+                Shape.Square(side) => side * side * scale,
+            }
+        }
+        var total = area(Shape.Circle(2)) + area(scale: 2, shape: Shape.Square(1.5));
+        total + 0.5
+    ";
+
+    fn save(chunk: &Chunk) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        chunk.save(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trips() {
+        let chunk = compile(SOURCE).unwrap();
+        let bytes = save(&chunk);
+        let loaded = Chunk::load(bytes.as_slice()).unwrap();
+
+        assert_eq!(loaded, chunk);
+        assert_eq!(save(&loaded), bytes);
+        assert_eq!(VM::new(loaded.clone()).run(), Ok(Value::Float(17.0)));
+
+        // Constants added after loading are shared like the compiler's.
+        let mut extended = loaded;
+        let len = extended.constants.len();
+        let total = extended.add_constant(Value::String("total".into()));
+        assert!(total < len);
+        assert_eq!(extended.constants[total], Value::String("total".into()));
+        assert_eq!(extended.add_constant(Value::Integer(-7)), len);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let bytes = save(&compile(SOURCE).unwrap());
+        let load = |bytes: &[u8]| Chunk::load(bytes).unwrap_err();
+
+        assert!(matches!(load(b"LOF"), BinaryError::NotBytecode));
+        assert!(matches!(
+            load(b"#!/usr/bin/lof\n"),
+            BinaryError::NotBytecode
+        ));
+
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            load(&newer),
+            BinaryError::UnsupportedVersion(version) if version == FORMAT_VERSION + 1
+        ));
+
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(load(&corrupted), BinaryError::ChecksumMismatch));
+
+        assert!(matches!(
+            load(&bytes[..bytes.len() - 1]),
+            BinaryError::Malformed(_)
+        ));
+
+        let mut underflow = Chunk::new();
+        underflow.write_operation(OpCode::Pop, [], 1, 1);
+        underflow.write_operation(OpCode::Return, [], 1, 1);
+        assert!(matches!(load(&save(&underflow)), BinaryError::Invalid(_)));
+
+        let mut list = Chunk::new();
+        list.add_constant(Value::new_list(Vec::new()));
+        assert!(matches!(
+            list.save(Vec::new()).unwrap_err(),
+            BinaryError::Unsaveable(_)
+        ));
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
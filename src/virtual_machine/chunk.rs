@@ -1,16 +1,261 @@
+use std::collections::HashMap;
 use std::error::Error;
+use std::fmt::Write as _;
 use std::io::BufWriter;
 use std::io::Write;
+use std::rc::Rc;
 
+use super::function::Function;
 use super::op_code::OpCode;
 use super::value::Value;
 
 #[derive(Default, Clone, Debug)]
 pub struct Chunk {
     pub code: Vec<u8>,
-    pub lines: Vec<usize>,
+    pub lines: LineTable,
     pub constants: Vec<Value>,
-    pub name: Option<&'static str>,
+    pub name: Option<Rc<str>>,
+    /// Where the constants that can be shared already are in the pool.
+    shared_constants: HashMap<ConstantKey, usize>,
+}
+
+/// Identifies a constant by what it is rather than by how it compares, so
+/// `1` and `1.0` or `0.0` and `-0.0` don't share a slot.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Nil,
+    Bool(bool),
+    Integer(i64),
+    Float(u64),
+    String(Rc<str>),
+}
+
+impl ConstantKey {
+    fn new(value: &Value) -> Option<Self> {
+        Some(match value {
+            Value::Nil => ConstantKey::Nil,
+            Value::Bool(b) => ConstantKey::Bool(*b),
+            Value::Integer(i) => ConstantKey::Integer(*i),
+            Value::Float(f) => ConstantKey::Float(f.to_bits()),
+            Value::String(s) => ConstantKey::String(s.clone()),
+            _ => return None,
+        })
+    }
+}
+
+/// The source line and column of every byte of code, stored once for each
+/// run of bytes from the same place.
+///
+/// Each run is stored as how far it starts, and how far its line and column
+/// are, from the run before it, which usually fits in two bytes. Every
+/// `CHECKPOINT_EVERY`th run is also kept whole, so a lookup only decodes the
+/// runs after the nearest one.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct LineTable {
+    bytes: Vec<u8>,
+    checkpoints: Vec<Checkpoint>,
+    /// The last run, which the next byte either extends or follows.
+    last: Option<LineRun>,
+    runs: usize,
+    len: usize,
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct LineRun {
+    /// The offset of the first byte from the place.
+    pub start: usize,
+    pub line: usize,
+    /// Counted in characters from 1, or 0 if it isn't known.
+    pub column: usize,
+}
+
+const CHECKPOINT_EVERY: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Checkpoint {
+    run: LineRun,
+    /// Where the run after it starts in the encoded bytes.
+    next: usize,
+}
+
+impl LineTable {
+    /// Records the line and column of the next byte.
+    pub fn push(&mut self, line: usize, column: usize) {
+        if self
+            .last
+            .is_none_or(|run| (run.line, run.column) != (line, column))
+        {
+            self.push_run(LineRun {
+                start: self.len,
+                line,
+                column,
+            });
+        }
+        self.len += 1;
+    }
+
+    fn push_run(&mut self, run: LineRun) {
+        encode_run(&mut self.bytes, self.last.unwrap_or_default(), run);
+        if self.runs > 0 && self.runs.is_multiple_of(CHECKPOINT_EVERY) {
+            self.checkpoints.push(Checkpoint {
+                run,
+                next: self.bytes.len(),
+            });
+        }
+        self.last = Some(run);
+        self.runs += 1;
+    }
+
+    pub fn get(&self, offset: usize) -> Option<usize> {
+        self.run(offset).map(|run| run.line)
+    }
+
+    pub fn column(&self, offset: usize) -> Option<usize> {
+        self.run(offset).map(|run| run.column)
+    }
+
+    fn run(&self, offset: usize) -> Option<LineRun> {
+        if offset >= self.len {
+            return None;
+        }
+        let checkpoint = self
+            .checkpoints
+            .partition_point(|checkpoint| checkpoint.run.start <= offset);
+        let (mut run, mut next) = match checkpoint.checked_sub(1) {
+            Some(checkpoint) => (
+                self.checkpoints[checkpoint].run,
+                self.checkpoints[checkpoint].next,
+            ),
+            None => {
+                let mut next = 0;
+                (decode_run(&self.bytes, &mut next, LineRun::default()), next)
+            }
+        };
+        while next < self.bytes.len() {
+            let mut after = next;
+            let following = decode_run(&self.bytes, &mut after, run);
+            if following.start > offset {
+                break;
+            }
+            (run, next) = (following, after);
+        }
+        Some(run)
+    }
+
+    /// How many bytes have a line.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// How many bytes the table takes up, not counting its fixed size.
+    pub fn size(&self) -> usize {
+        self.bytes.len() + self.checkpoints.len() * std::mem::size_of::<Checkpoint>()
+    }
+
+    pub fn runs(&self) -> impl Iterator<Item = LineRun> + '_ {
+        let mut next = 0;
+        let mut run = LineRun::default();
+        std::iter::from_fn(move || {
+            (next < self.bytes.len()).then(|| {
+                run = decode_run(&self.bytes, &mut next, run);
+                run
+            })
+        })
+    }
+
+    /// A table for `len` bytes of code, or `None` unless the runs start at
+    /// increasing offsets within the code, beginning with the first byte.
+    pub fn from_runs(runs: Vec<LineRun>, len: usize) -> Option<Self> {
+        let starts_in_order = runs.windows(2).all(|pair| pair[0].start < pair[1].start);
+        let covers_code = match runs.first() {
+            Some(first) => first.start == 0 && runs.last()?.start < len,
+            None => len == 0,
+        };
+        if !(starts_in_order && covers_code) {
+            return None;
+        }
+        let mut table = Self::default();
+        for run in runs {
+            table.push_run(run);
+        }
+        table.len = len;
+        Some(table)
+    }
+}
+
+/// Writes `run` after `previous`. The first byte holds how far it starts and
+/// how many lines down it is, up to 14 each; 15 means the distance follows
+/// as a varint. Then comes how far the column moved.
+fn encode_run(out: &mut Vec<u8>, previous: LineRun, run: LineRun) {
+    let start = run.start - previous.start;
+    let line = run.line as i64 - previous.line as i64;
+    let start_nibble = start.min(15) as u8;
+    let line_nibble = if (0..15).contains(&line) {
+        line as u8
+    } else {
+        15
+    };
+    out.push(start_nibble << 4 | line_nibble);
+    if start_nibble == 15 {
+        write_varint(out, (start - 15) as u64);
+    }
+    if line_nibble == 15 {
+        write_varint(out, zigzag(line));
+    }
+    write_varint(out, zigzag(run.column as i64 - previous.column as i64));
+}
+
+fn decode_run(bytes: &[u8], at: &mut usize, previous: LineRun) -> LineRun {
+    let nibbles = bytes[*at];
+    *at += 1;
+    let mut start = (nibbles >> 4) as usize;
+    if start == 15 {
+        start += read_varint(bytes, at) as usize;
+    }
+    let mut line = (nibbles & 0xf) as i64;
+    if line == 15 {
+        line = unzigzag(read_varint(bytes, at));
+    }
+    let column = unzigzag(read_varint(bytes, at));
+    LineRun {
+        start: previous.start + start,
+        line: (previous.line as i64 + line) as usize,
+        column: (previous.column as i64 + column) as usize,
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], at: &mut usize) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*at];
+        *at += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte < 0x80 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
 }
 
 impl Chunk {
@@ -18,26 +263,59 @@ impl Chunk {
         Self::default()
     }
 
-    pub fn new_named(name: &'static str) -> Self {
+    pub fn new_named(name: &str) -> Self {
         Self {
-            name: Some(name),
+            name: Some(name.into()),
             ..Default::default()
         }
     }
 
-    pub fn write_op_code(&mut self, op: OpCode, line_number: usize) {
+    pub fn write_op_code(&mut self, op: OpCode, line_number: usize, column: usize) {
         self.code.push(op.into());
-        self.lines.push(line_number);
+        self.lines.push(line_number, column);
     }
 
-    pub fn write_operand(&mut self, operand: u8, line_number: usize) {
+    pub fn write_operand(&mut self, operand: u8, line_number: usize, column: usize) {
         self.code.push(operand);
-        self.lines.push(line_number);
+        self.lines.push(line_number, column);
     }
 
-    pub fn add_constant(&mut self, val: Value) -> u8 {
+    /// Adds `val` to the constants, unless an identical constant is already
+    /// there, and returns its index.
+    pub fn add_constant(&mut self, val: Value) -> usize {
+        let key = ConstantKey::new(&val);
+        if let Some(&index) = key.as_ref().and_then(|key| self.shared_constants.get(key)) {
+            return index;
+        }
+
         self.constants.push(val);
-        (self.constants.len() - 1) as u8
+        let index = self.constants.len() - 1;
+        if let Some(key) = key {
+            self.shared_constants.insert(key, index);
+        }
+        index
+    }
+
+    /// Finds the constants that can be shared again after `constants` was
+    /// filled in directly, as when loading a chunk. Where a constant repeats,
+    /// the first one is shared.
+    pub fn share_constants(&mut self) {
+        self.shared_constants.clear();
+        for (index, constant) in self.constants.iter().enumerate() {
+            if let Some(key) = ConstantKey::new(constant) {
+                self.shared_constants.entry(key).or_insert(index);
+            }
+        }
+    }
+
+    /// The source line of the code at `offset`, or 0 if there is none.
+    pub fn line_for_offset(&self, offset: usize) -> usize {
+        self.lines.get(offset).unwrap_or(0)
+    }
+
+    /// The source column of the code at `offset`, or 0 if there is none.
+    pub fn column_for_offset(&self, offset: usize) -> usize {
+        self.lines.column(offset).unwrap_or(0)
     }
 
     pub fn write_operation<const NUM_OPERANDS: usize>(
@@ -45,27 +323,91 @@ impl Chunk {
         op: OpCode,
         operands: [u8; NUM_OPERANDS],
         line_number: usize,
+        column: usize,
     ) {
         assert!(NUM_OPERANDS == op.num_operands());
-        self.write_op_code(op, line_number);
+        self.write_op_code(op, line_number, column);
 
-        match op {
-            OpCode::Constant => self.write_operand(operands[0], line_number),
-            _ => {}
+        for operand in operands {
+            self.write_operand(operand, line_number, column);
         }
     }
 
+    /// The chunk as text, which `assembler::assemble` turns back into the
+    /// same chunk: a header with its name, its constants, with the chunks of
+    /// functions indented below them, and its code.
     pub fn disassemble(&self) -> Result<String, Box<dyn Error>> {
         let mut buffer = BufWriter::new(Vec::new());
+        self.disassemble_into(&mut buffer, "")?;
+        Ok(String::from_utf8(buffer.into_inner()?)?)
+    }
 
-        let mut offset = 0;
+    fn disassemble_into(
+        &self,
+        buffer: &mut BufWriter<Vec<u8>>,
+        indent: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        writeln!(
+            buffer,
+            "{indent}{:=^50}",
+            self.name.as_deref().unwrap_or("")
+        )?;
 
-        write!(&mut buffer, "{:=^50}", self.name.unwrap_or(""))?;
+        for constant in &self.constants {
+            match constant {
+                Value::Function(function) if function.defaults.is_empty() => {
+                    writeln!(buffer, "{indent}.const {}", function_header(function))?;
+                    function
+                        .chunk
+                        .disassemble_into(buffer, &format!("{indent}    "))?;
+                    writeln!(buffer, "{indent}}}")?;
+                }
+                _ => {
+                    let literal = constant_literal(constant)
+                        .ok_or_else(|| format!("{constant} can't be disassembled"))?;
+                    writeln!(buffer, "{indent}.const {literal}")?;
+                }
+            }
+        }
 
+        let mut offset = 0;
         while offset < self.code.len() {
-            offset = disassemble_operation_write(self, offset, &mut buffer)?;
+            write!(buffer, "{indent}")?;
+            offset = disassemble_operation_write(self, offset, buffer)?;
+            writeln!(buffer)?;
         }
-        Ok(String::from_utf8(buffer.into_inner()?)?)
+        Ok(())
+    }
+}
+
+/// Chunks are equal when they have the same name, code, lines and
+/// constants. Unlike values, functions among the constants are compared by
+/// what they're made of.
+impl PartialEq for Chunk {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.code == other.code
+            && self.lines == other.lines
+            && self.constants.len() == other.constants.len()
+            && self
+                .constants
+                .iter()
+                .zip(&other.constants)
+                .all(|(a, b)| same_constant(a, b))
+    }
+}
+
+fn same_constant(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Function(a), Value::Function(b)) => {
+            a.name == b.name
+                && a.params == b.params
+                && a.variadic == b.variadic
+                && a.defaults == b.defaults
+                && a.chunk == b.chunk
+        }
+        (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(),
+        _ => a == b,
     }
 }
 
@@ -78,34 +420,191 @@ pub fn disassemble_operation_write(
 
     write!(buffer, "{:>4}", offset)?;
 
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
-        write!(buffer, "     |")?;
-    } else {
-        write!(buffer, " {:>5}", chunk.lines[offset])?;
+    let position = |offset| {
+        (
+            chunk.line_for_offset(offset),
+            chunk.column_for_offset(offset),
+        )
+    };
+    match position(offset) {
+        _ if offset > 0 && position(offset) == position(offset - 1) => write!(buffer, "     |")?,
+        (line, 0) => write!(buffer, " {:>5}", line)?,
+        (line, column) => write!(buffer, " {:>5}", format!("{line}:{column}"))?,
     }
 
     let op: OpCode = code[offset].try_into()?;
     write!(buffer, "\t{}", op)?;
     let n_operands = op.num_operands();
+    if offset + n_operands >= code.len() {
+        return Err("truncated instruction".into());
+    }
     for i in 1..=n_operands {
         write!(buffer, " {}", code[offset + i])?;
     }
 
-    write!(buffer, "\t")?;
-    for i in 1..=n_operands {
-        write!(
-            buffer,
-            "[{}]: {}; ",
-            code[offset + i],
-            chunk.constants[code[offset + i] as usize]
-        )?;
+    if let Some(operand) = op.jump_operand() {
+        let at = offset + 1 + operand;
+        let jump = u16::from_be_bytes([code[at], code[at + 1]]) as usize;
+        let next = offset + 1 + n_operands;
+        let target = match op {
+            OpCode::Loop => next.checked_sub(jump).ok_or("jump out of bounds")?,
+            _ => next + jump,
+        };
+        write!(buffer, "\t-> {}", target)?;
+    }
+
+    if let Some(index) = op.constant_index(&code[offset + 1..=offset + n_operands]) {
+        let constant = chunk.constants.get(index).ok_or("invalid constant index")?;
+        match constant_literal(constant) {
+            Some(literal) => write!(buffer, "\t[{}]: {}", index, literal)?,
+            None => write!(buffer, "\t[{}]: {}", index, constant)?,
+        }
     }
 
     Ok(offset + 1 + n_operands)
 }
 
+/// Like `disassemble_operation_write`, with the error in place of the
+/// operation if it can't be decoded.
 pub fn disassemble_operation(chunk: &Chunk, offset: usize) -> String {
     let mut buffer = BufWriter::new(Vec::new());
-    disassemble_operation_write(chunk, offset, &mut buffer).unwrap();
-    String::from_utf8(buffer.into_inner().unwrap()).unwrap()
+    let result = disassemble_operation_write(chunk, offset, &mut buffer);
+    let text = buffer
+        .into_inner()
+        .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+        .unwrap_or_default();
+
+    match result {
+        Ok(_) => text,
+        Err(err) => format!("{text}\t<{err}>"),
+    }
+}
+
+/// How a constant is written, or `None` for functions, which are written
+/// as blocks, and values that only exist at runtime.
+fn constant_literal(value: &Value) -> Option<String> {
+    let mut literal = String::new();
+    write_literal(&mut literal, value)?;
+    Some(literal)
+}
+
+fn write_literal(out: &mut String, value: &Value) -> Option<()> {
+    match value {
+        Value::Nil => out.push_str("nil"),
+        Value::Bool(b) => write!(out, "{b}").ok()?,
+        Value::Integer(i) => write!(out, "{i}").ok()?,
+        Value::Float(f) => write!(out, "{f:?}").ok()?,
+        Value::String(s) => write!(out, "{:?}", &**s).ok()?,
+        Value::Tuple(elements) => {
+            write_elements(out, elements)?;
+            // `(1,)` is a tuple, but `(1)` would just be `1`.
+            if elements.len() == 1 {
+                out.insert(out.len() - 1, ',');
+            }
+        }
+        Value::Enum(value) => {
+            let variant = &value.variant;
+            write!(
+                out,
+                "{}.{}/{}",
+                variant.enum_name, variant.name, variant.arity
+            )
+            .ok()?;
+            if !value.payload.is_empty() {
+                write_elements(out, &value.payload)?;
+            }
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+fn write_elements(out: &mut String, elements: &[Value]) -> Option<()> {
+    out.push('(');
+    for (i, element) in elements.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        write_literal(out, element)?;
+    }
+    out.push(')');
+    Some(())
+}
+
+/// How a function is written before its chunk, `fn name(params) {`.
+fn function_header(function: &Function) -> String {
+    let params: Vec<String> = function
+        .params
+        .iter()
+        .enumerate()
+        .map(|(i, param)| {
+            let rest = function.variadic && i + 1 == function.params.len();
+            format!(
+                "{}{}{}",
+                if rest { ".." } else { "" },
+                param.name.as_deref().unwrap_or("_"),
+                if param.has_default { "?" } else { "" }
+            )
+        })
+        .collect();
+    format!("fn {}({}) {{", function.name, params.join(", "))
+}
+
+#[cfg(test)]
+mod test {
+    use super::LineTable;
+    use crate::compiler::compiler::compile;
+    use crate::virtual_machine::value::Value;
+
+    #[test]
+    fn line_table() {
+        let mut lines = LineTable::default();
+        for (line, column) in [(1, 1), (1, 1), (1, 1), (2, 3), (4, 1), (4, 5), (1, 1)] {
+            lines.push(line, column);
+        }
+
+        assert_eq!(lines.runs().count(), 5);
+        assert_eq!(lines.len(), 7);
+        assert_eq!(lines.get(2), Some(1));
+        assert_eq!(lines.get(3), Some(2));
+        assert_eq!(lines.get(5), Some(4));
+        assert_eq!(lines.get(6), Some(1));
+        assert_eq!(lines.get(7), None);
+        assert_eq!(lines.column(3), Some(3));
+        assert_eq!(lines.column(4), Some(1));
+        assert_eq!(lines.column(5), Some(5));
+    }
+
+    #[test]
+    fn line_table_checkpoints() {
+        let mut lines = LineTable::default();
+        let positions: Vec<_> = (0..1000)
+            .map(|i| (1 + i / 3 % 40, 1 + i * 7 % 200))
+            .collect();
+        for &(line, column) in &positions {
+            lines.push(line, column);
+        }
+
+        for (offset, &(line, column)) in positions.iter().enumerate() {
+            assert_eq!(lines.get(offset), Some(line));
+            assert_eq!(lines.column(offset), Some(column));
+        }
+        let runs: Vec<_> = lines.runs().collect();
+        assert_eq!(LineTable::from_runs(runs, lines.len()), Some(lines));
+    }
+
+    #[test]
+    fn line_table_is_smaller_than_code() {
+        let chunk = compile(
+            "fn fib(n) {\n  if n < 2 { return n; }\n  return fib(n - 1) + fib(n - 2);\n}\nprint fib(10);\n",
+        )
+        .unwrap();
+        let Some(Value::Function(fib)) = chunk.constants.first() else {
+            panic!("expected the function first");
+        };
+
+        for chunk in [&chunk, &fib.chunk] {
+            assert!(chunk.lines.size() < chunk.code.len());
+        }
+    }
 }
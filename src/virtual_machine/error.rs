@@ -0,0 +1,95 @@
+use std::{fmt::Display, rc::Rc};
+
+use super::value::{Value, Variant};
+
+/// A call that was running when a runtime error went uncaught.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    pub function: Rc<str>,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    /// A value was thrown and nothing caught it.
+    Uncaught,
+    /// The script was stopped through an `InterruptHandle`.
+    Interrupted,
+    /// The script went past the stack's or the call stack's limit, and
+    /// didn't catch the `RuntimeError.StackOverflow` it got.
+    StackOverflow,
+    /// The heap went past its limit even after collecting garbage, and the
+    /// script didn't catch the `RuntimeError.OutOfMemory` it got.
+    OutOfMemory,
+}
+
+impl ErrorKind {
+    /// The `RuntimeError` variant that's thrown for errors of this kind, if
+    /// scripts get to catch them.
+    pub fn variant(self) -> Option<Variant> {
+        let [stack_overflow, out_of_memory] = Variant::runtime_error_variants();
+        match self {
+            ErrorKind::StackOverflow => Some(stack_overflow),
+            ErrorKind::OutOfMemory => Some(out_of_memory),
+            ErrorKind::Uncaught | ErrorKind::Interrupted => None,
+        }
+    }
+
+    /// What kind of error a value that nothing caught makes.
+    pub fn of_thrown(thrown: &Value) -> Self {
+        [ErrorKind::StackOverflow, ErrorKind::OutOfMemory]
+            .into_iter()
+            .find(|kind| {
+                kind.variant()
+                    .is_some_and(|variant| thrown.is_variant(&variant))
+            })
+            .unwrap_or(ErrorKind::Uncaught)
+    }
+
+    fn message(self) -> Option<&'static str> {
+        match self {
+            ErrorKind::StackOverflow => Some("stack overflow"),
+            ErrorKind::OutOfMemory => Some("out of memory"),
+            ErrorKind::Uncaught | ErrorKind::Interrupted => None,
+        }
+    }
+}
+
+/// An error the script didn't catch, or couldn't.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// The line of the instruction that failed.
+    pub line: usize,
+    pub column: usize,
+    /// The calls that led to the error, innermost first.
+    pub trace: Vec<TraceFrame>,
+}
+
+impl RuntimeError {
+    /// The message for a value that nothing caught.
+    pub fn uncaught_message(thrown: &Value) -> String {
+        match ErrorKind::of_thrown(thrown).message() {
+            Some(message) => message.to_owned(),
+            None => thrown.to_string(),
+        }
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            write!(
+                f,
+                "\n[line {}, column {}] in {}",
+                frame.line, frame.column, frame.function
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
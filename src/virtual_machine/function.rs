@@ -0,0 +1,236 @@
+use std::rc::Rc;
+
+use thiserror::Error;
+
+use super::{chunk::Chunk, value::Value};
+
+#[derive(Debug, PartialEq)]
+pub struct Parameter {
+    /// `None` for destructured parameters, which can only be passed by
+    /// position.
+    pub name: Option<Rc<str>>,
+    pub has_default: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: Rc<str>,
+    pub chunk: Rc<Chunk>,
+    pub params: Rc<[Parameter]>,
+    /// Whether the last parameter collects the remaining positional
+    /// arguments into a list.
+    pub variadic: bool,
+    /// The values of the parameters that have defaults, in order. They are
+    /// evaluated once, where the function is declared.
+    pub defaults: Vec<Value>,
+}
+
+/// Functions are only equal to themselves.
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum CallError {
+    #[error("{function}() takes at most {max} positional arguments but {given} were given")]
+    TooManyArguments {
+        function: Rc<str>,
+        max: usize,
+        given: usize,
+    },
+    #[error("{function}() has no parameter named '{name}'")]
+    UnknownArgument { function: Rc<str>, name: Rc<str> },
+    #[error("{function}() got more than one value for '{name}'")]
+    DuplicateArgument { function: Rc<str>, name: Rc<str> },
+    #[error("{function}() is missing a value for {parameter}")]
+    MissingArgument {
+        function: Rc<str>,
+        parameter: String,
+    },
+}
+
+impl Function {
+    /// The script's top level, which takes no arguments.
+    pub fn script(chunk: Chunk) -> Self {
+        Self {
+            name: "script".into(),
+            chunk: Rc::new(chunk),
+            params: Rc::new([]),
+            variadic: false,
+            defaults: Vec::new(),
+        }
+    }
+
+    /// Orders the arguments of a call the way the parameters are laid out on
+    /// the stack. The last `names.len()` arguments are passed by name,
+    /// missing ones take their default, and extra positional ones go to a
+    /// variadic parameter.
+    pub fn bind_arguments(
+        &self,
+        mut args: Vec<Value>,
+        names: &[Rc<str>],
+    ) -> Result<Vec<Value>, CallError> {
+        let fixed = self.params.len() - self.variadic as usize;
+
+        let named = args.split_off(args.len() - names.len());
+        let mut positional = args;
+        let rest = if positional.len() > fixed {
+            if !self.variadic {
+                return Err(CallError::TooManyArguments {
+                    function: self.name.clone(),
+                    max: fixed,
+                    given: positional.len(),
+                });
+            }
+            positional.split_off(fixed)
+        } else {
+            Vec::new()
+        };
+
+        let mut slots: Vec<_> = positional.into_iter().map(Some).collect();
+        slots.resize(fixed, None);
+
+        for (name, value) in names.iter().zip(named) {
+            let index = self.params[..fixed]
+                .iter()
+                .position(|param| param.name.as_ref() == Some(name))
+                .ok_or_else(|| CallError::UnknownArgument {
+                    function: self.name.clone(),
+                    name: name.clone(),
+                })?;
+
+            if slots[index].replace(value).is_some() {
+                return Err(CallError::DuplicateArgument {
+                    function: self.name.clone(),
+                    name: name.clone(),
+                });
+            }
+        }
+
+        let mut defaults = self.defaults.iter();
+        let mut bound = Vec::with_capacity(self.params.len());
+        for (i, (param, slot)) in self.params.iter().zip(slots).enumerate() {
+            let default = param.has_default.then(|| defaults.next()).flatten();
+            match slot.or_else(|| default.cloned()) {
+                Some(value) => bound.push(value),
+                None => {
+                    return Err(CallError::MissingArgument {
+                        function: self.name.clone(),
+                        parameter: match &param.name {
+                            Some(name) => format!("'{name}'"),
+                            None => format!("parameter {}", i + 1),
+                        },
+                    })
+                }
+            }
+        }
+
+        if self.variadic {
+            bound.push(Value::new_list(rest));
+        }
+
+        Ok(bound)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::virtual_machine::{chunk::Chunk, value::Value};
+
+    use super::{Function, Parameter};
+
+    fn parameter(name: Option<&str>, has_default: bool) -> Parameter {
+        Parameter {
+            name: name.map(Into::into),
+            has_default,
+        }
+    }
+
+    /// `fn f(a, b = 2, c, ..rest)`
+    fn function() -> Function {
+        Function {
+            name: "f".into(),
+            chunk: Rc::new(Chunk::new()),
+            params: Rc::new([
+                parameter(Some("a"), false),
+                parameter(Some("b"), true),
+                parameter(Some("c"), false),
+                parameter(Some("rest"), false),
+            ]),
+            variadic: true,
+            defaults: vec![Value::Integer(2)],
+        }
+    }
+
+    fn bind(function: &Function, args: &[i64], names: &[&str]) -> Result<Vec<Value>, String> {
+        let args = args.iter().map(|&arg| Value::Integer(arg)).collect();
+        let names: Vec<Rc<str>> = names.iter().map(|&name| name.into()).collect();
+        function
+            .bind_arguments(args, &names)
+            .map_err(|err| err.to_string())
+    }
+
+    fn bound(args: &[i64], rest: &[i64]) -> Result<Vec<Value>, String> {
+        let mut bound: Vec<_> = args.iter().map(|&arg| Value::Integer(arg)).collect();
+        bound.push(
+            rest.iter()
+                .map(|&arg| Value::Integer(arg))
+                .collect::<Vec<_>>()
+                .into(),
+        );
+        Ok(bound)
+    }
+
+    #[test]
+    fn binds_arguments() {
+        let f = function();
+
+        assert_eq!(bind(&f, &[1, 5, 3], &[]), bound(&[1, 5, 3], &[]));
+        assert_eq!(bind(&f, &[1, 5, 3, 4, 6], &[]), bound(&[1, 5, 3], &[4, 6]));
+        assert_eq!(bind(&f, &[1, 3], &["c"]), bound(&[1, 2, 3], &[]));
+        assert_eq!(
+            bind(&f, &[3, 1, 5], &["c", "a", "b"]),
+            bound(&[1, 5, 3], &[])
+        );
+    }
+
+    #[test]
+    fn reports_bad_calls() {
+        let f = function();
+        let error = |args: &[i64], names: &[&str]| bind(&f, args, names).unwrap_err();
+
+        assert_eq!(error(&[1], &[]), "f() is missing a value for 'c'");
+        assert_eq!(error(&[1, 2], &["d"]), "f() has no parameter named 'd'");
+        assert_eq!(
+            error(&[1, 2], &["rest"]),
+            "f() has no parameter named 'rest'"
+        );
+        assert_eq!(
+            error(&[1, 2, 3], &["a"]),
+            "f() got more than one value for 'a'"
+        );
+
+        let g = Function {
+            variadic: false,
+            ..function()
+        };
+        assert_eq!(
+            bind(&g, &[1, 2, 3, 4, 5], &[]).unwrap_err(),
+            "f() takes at most 4 positional arguments but 5 were given"
+        );
+
+        let h = Function {
+            params: Rc::new([parameter(None, false)]),
+            variadic: false,
+            ..function()
+        };
+        assert_eq!(
+            bind(&h, &[], &[]).unwrap_err(),
+            "f() is missing a value for parameter 1"
+        );
+    }
+}
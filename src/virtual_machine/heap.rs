@@ -0,0 +1,633 @@
+//! Values are reference counted, which frees everything except cycles. The
+//! only objects that can form a cycle are lists and weak maps, since they
+//! are the only ones that can be changed after they are made, so the heap
+//! keeps track of every one the VM allocates. A collection marks everything
+//! reachable from the roots and empties the ones it didn't reach, which
+//! breaks their cycles and lets reference counting free them.
+//!
+//! A weak map's value is only reached if its key is, and entries whose keys
+//! weren't are removed, even when the value refers back to the key.
+//!
+//! The heap also keeps track of every other object the VM allocates, to
+//! account for the memory they use. Objects freed by reference counting are
+//! only noticed by the next collection, so in between the count is of the
+//! bytes allocated rather than the bytes in use.
+//!
+//! Marking can be done all at once, or a little at a time in between
+//! instructions to keep pauses short. While marking is spread out, the
+//! program keeps changing what refers to what, so stores into lists and
+//! globals go through a write barrier that marks the stored value, and the
+//! stack is marked again before sweeping.
+//!
+//! An object can be given a finalizer, which the VM calls once a collection
+//! finds the object unreachable, or finds that it has been freed. By then
+//! the object is gone, so the finalizer isn't given it, and anything the
+//! finalizer refers to is kept alive, which keeps an object that its own
+//! finalizer refers to from ever being finalized.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    mem::size_of,
+    rc::{Rc, Weak},
+    time::Duration,
+};
+
+use super::{
+    function::Function,
+    value::{EnumValue, Value},
+    weak::{WeakMap, WeakRef},
+};
+
+/// How many bytes have to be allocated before the first collection.
+const INITIAL_THRESHOLD: usize = 1 << 20;
+/// How much the heap may grow, relative to what survived, before the next
+/// collection.
+const GROWTH_FACTOR: usize = 2;
+
+type List = RefCell<Vec<Value>>;
+
+/// An object the VM allocated. Lists and weak maps can be part of a cycle,
+/// the rest are only accounted for.
+enum Object {
+    List(Weak<List>),
+    WeakMap(Weak<RefCell<WeakMap>>),
+    String(Weak<str>),
+    Tuple(Weak<[Value]>),
+    Enum(Weak<EnumValue>),
+    Function(Weak<Function>),
+}
+
+impl Object {
+    fn new(value: &Value) -> Option<(usize, Self)> {
+        Some(match value {
+            Value::List(list) => (address(list), Object::List(Rc::downgrade(list))),
+            Value::WeakMap(map) => (address(map), Object::WeakMap(Rc::downgrade(map))),
+            Value::String(string) => (address(string), Object::String(Rc::downgrade(string))),
+            Value::Tuple(tuple) => (address(tuple), Object::Tuple(Rc::downgrade(tuple))),
+            Value::Enum(value) => (address(value), Object::Enum(Rc::downgrade(value))),
+            Value::Function(function) => {
+                (address(function), Object::Function(Rc::downgrade(function)))
+            }
+            _ => return None,
+        })
+    }
+
+    /// Roughly how many bytes the object takes up, including the reference
+    /// counts, or `None` if it has been freed.
+    fn size(&self) -> Option<usize> {
+        const VALUE: usize = size_of::<Value>();
+        let contents = match self {
+            Object::List(list) => size_of::<List>() + list.upgrade()?.borrow().len() * VALUE,
+            Object::WeakMap(map) => {
+                size_of::<RefCell<WeakMap>>()
+                    + map.upgrade()?.borrow().entries.len() * WEAK_MAP_ENTRY
+            }
+            Object::String(string) => string.upgrade()?.len(),
+            Object::Tuple(tuple) => tuple.upgrade()?.len() * VALUE,
+            Object::Enum(value) => size_of::<EnumValue>() + value.upgrade()?.payload.len() * VALUE,
+            Object::Function(function) => {
+                size_of::<Function>() + function.upgrade()?.defaults.len() * VALUE
+            }
+        };
+        Some(2 * size_of::<usize>() + contents)
+    }
+}
+
+/// How many bytes an entry adds to a weak map.
+pub const WEAK_MAP_ENTRY: usize = size_of::<(WeakRef, Value)>();
+
+fn address<T: ?Sized>(object: &Rc<T>) -> usize {
+    Rc::as_ptr(object) as *const () as usize
+}
+
+/// Finds the objects reachable from the roots it's given. Marked objects
+/// whose references haven't been followed yet are gray, the rest are black.
+#[derive(Default)]
+pub struct Marker {
+    marked: HashSet<usize>,
+    gray: Vec<Value>,
+    /// The weak maps reached so far, whose values are marked as their keys
+    /// are.
+    weak_maps: Vec<Rc<RefCell<WeakMap>>>,
+    /// What marking started from, to check the sweep against.
+    #[cfg(debug_assertions)]
+    roots: Vec<Value>,
+}
+
+impl Marker {
+    /// Marks a root, or a value stored behind the marking's back. Returns
+    /// whether the value wasn't marked before.
+    pub fn mark(&mut self, value: &Value) -> bool {
+        #[cfg(debug_assertions)]
+        self.roots.push(value.clone());
+        self.mark_value(value)
+    }
+
+    fn mark_value(&mut self, value: &Value) -> bool {
+        let address = match value {
+            Value::List(list) => address(list),
+            Value::Tuple(tuple) => address(tuple),
+            Value::Enum(value) => address(value),
+            Value::Function(function) => address(function),
+            Value::WeakMap(map) => address(map),
+            _ => return false,
+        };
+        let new = self.marked.insert(address);
+        if new {
+            self.gray.push(value.clone());
+        }
+        new
+    }
+
+    pub fn mark_function(&mut self, function: &Rc<Function>) {
+        self.mark(&Value::Function(function.clone()));
+    }
+
+    /// Follows the references of gray objects until there are none left.
+    pub fn trace(&mut self) {
+        loop {
+            while let Some(value) = self.gray.pop() {
+                self.blacken(&value);
+            }
+            if !self.mark_weak_map_values() {
+                break;
+            }
+        }
+    }
+
+    /// Marks the values of weak map entries whose keys have been marked
+    /// since their map was. Returns whether there were any.
+    fn mark_weak_map_values(&mut self) -> bool {
+        let mut found = false;
+        for i in 0..self.weak_maps.len() {
+            let map = self.weak_maps[i].clone();
+            for (key, value) in &map.borrow().entries {
+                if self.is_marked(key.address()) {
+                    found |= self.mark_value(value);
+                }
+            }
+        }
+        found
+    }
+
+    /// Follows the references of up to `budget` gray objects. Returns
+    /// whether there are none left.
+    pub fn trace_some(&mut self, budget: usize) -> bool {
+        for _ in 0..budget {
+            let Some(value) = self.gray.pop() else {
+                break;
+            };
+            self.blacken(&value);
+        }
+        self.gray.is_empty()
+    }
+
+    fn blacken(&mut self, value: &Value) {
+        match value {
+            Value::List(list) => {
+                for element in list.borrow().iter() {
+                    self.mark_value(element);
+                }
+            }
+            Value::Tuple(tuple) => {
+                for element in tuple.iter() {
+                    self.mark_value(element);
+                }
+            }
+            Value::Enum(value) => {
+                for element in &value.payload {
+                    self.mark_value(element);
+                }
+            }
+            Value::Function(function) => {
+                for value in function.defaults.iter().chain(&function.chunk.constants) {
+                    self.mark_value(value);
+                }
+            }
+            Value::WeakMap(map) => {
+                for (key, value) in &map.borrow().entries {
+                    if self.is_marked(key.address()) {
+                        self.mark_value(value);
+                    }
+                }
+                self.weak_maps.push(map.clone());
+            }
+            _ => {}
+        }
+    }
+
+    fn is_marked(&self, address: usize) -> bool {
+        self.marked.contains(&address)
+    }
+
+    /// Marks everything reachable from the roots all over again.
+    #[cfg(debug_assertions)]
+    fn remark(&self) -> Marker {
+        let mut marker = Marker::default();
+        for root in &self.roots {
+            marker.mark_value(root);
+        }
+        marker.trace();
+        marker
+    }
+}
+
+/// How the collector divides up its work.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GcMode {
+    /// Collect all at once when the heap has grown enough.
+    StopTheWorld,
+    /// Mark up to `budget` objects between instructions, then sweep.
+    Incremental { budget: usize },
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GcStats {
+    pub collections: usize,
+    /// How many objects collections have emptied.
+    pub freed: usize,
+    /// How many times the program was stopped to collect garbage.
+    pub pauses: usize,
+    pub total_pause: Duration,
+    pub longest_pause: Duration,
+}
+
+impl GcStats {
+    pub fn record_pause(&mut self, pause: Duration) {
+        self.pauses += 1;
+        self.total_pause += pause;
+        self.longest_pause = self.longest_pause.max(pause);
+    }
+}
+
+/// The objects the VM has allocated, how much memory they take up, and when
+/// to look for garbage among them.
+pub struct Heap {
+    objects: HashMap<usize, Object>,
+    bytes: usize,
+    peak_bytes: usize,
+    /// How many bytes the objects may take up.
+    limit: Option<usize>,
+    next_gc: usize,
+    /// Collect whenever anything was allocated, to shake out objects that
+    /// the roots miss.
+    stress: bool,
+    allocated: bool,
+    /// The marking in progress, if it's being done incrementally.
+    marking: Option<Marker>,
+    /// The objects that have finalizers, and their finalizers.
+    finalizers: Vec<(WeakRef, Value)>,
+    /// The finalizers of objects found unreachable, waiting to be called.
+    pending_finalizers: Vec<Value>,
+    pub stats: GcStats,
+}
+
+impl Heap {
+    pub fn new(stress: bool, limit: Option<usize>) -> Self {
+        Self {
+            objects: HashMap::new(),
+            bytes: 0,
+            peak_bytes: 0,
+            limit,
+            next_gc: INITIAL_THRESHOLD,
+            stress,
+            allocated: false,
+            marking: None,
+            finalizers: Vec::new(),
+            pending_finalizers: Vec::new(),
+            stats: GcStats::default(),
+        }
+    }
+
+    /// Starts keeping track of a newly allocated value. Values allocated
+    /// while marking are marked too, as they may hold the only references
+    /// to objects that haven't been.
+    pub fn track(&mut self, value: &Value) {
+        let Some((address, object)) = Object::new(value) else {
+            return;
+        };
+        // Values that were already there are often pushed again.
+        if self
+            .objects
+            .get(&address)
+            .is_some_and(|object| object.size().is_some())
+        {
+            return;
+        }
+
+        self.account(object.size().unwrap_or_default());
+        self.objects.insert(address, object);
+        self.allocated = true;
+        self.write_barrier(value);
+    }
+
+    /// Stops keeping track of a value that was just allocated, and is
+    /// being dropped.
+    pub fn untrack(&mut self, value: &Value) {
+        let Some((address, _)) = Object::new(value) else {
+            return;
+        };
+        if let Some(object) = self.objects.remove(&address) {
+            self.bytes = self.bytes.saturating_sub(object.size().unwrap_or_default());
+        }
+    }
+
+    /// Counts `bytes` more as being in use. Going over the limit doesn't
+    /// count towards the peak, as the VM either makes room or gives the
+    /// allocation up.
+    pub fn account(&mut self, bytes: usize) {
+        self.bytes += bytes;
+        if !self.over_limit() {
+            self.peak_bytes = self.peak_bytes.max(self.bytes);
+        }
+    }
+
+    pub fn bytes_in_use(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn peak_bytes(&self) -> usize {
+        self.peak_bytes
+    }
+
+    pub fn over_limit(&self) -> bool {
+        self.limit.is_some_and(|limit| self.bytes > limit)
+    }
+
+    /// Has to be called with every value stored into a list, a weak map or
+    /// a global while marking is in progress.
+    pub fn write_barrier(&mut self, value: &Value) {
+        if let Some(marker) = &mut self.marking {
+            marker.mark(value);
+        }
+    }
+
+    /// Has `finalizer` called once `object` is found unreachable. `None` if
+    /// `object` isn't an object.
+    pub fn add_finalizer(&mut self, object: &Value, finalizer: Value) -> Option<()> {
+        let object = WeakRef::new(object)?;
+        self.write_barrier(&finalizer);
+        self.finalizers.push((object, finalizer));
+        Some(())
+    }
+
+    /// The finalizers, which are roots, as they have to be there to be
+    /// called.
+    pub fn finalizers(&self) -> impl Iterator<Item = &Value> {
+        self.finalizers
+            .iter()
+            .map(|(_, finalizer)| finalizer)
+            .chain(&self.pending_finalizers)
+    }
+
+    /// The finalizers the last collections found due, in the order they
+    /// were added.
+    pub fn take_pending_finalizers(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.pending_finalizers)
+    }
+
+    pub fn is_marking(&self) -> bool {
+        self.marking.is_some()
+    }
+
+    /// Starts marking incrementally from the roots `marker` was given.
+    pub fn start_marking(&mut self, marker: Marker) {
+        self.marking = Some(marker);
+    }
+
+    /// Marks a little more. Returns whether marking is done, in which case
+    /// `take_marker` and `sweep` finish the collection.
+    pub fn mark_step(&mut self, budget: usize) -> bool {
+        self.marking
+            .as_mut()
+            .is_none_or(|marker| marker.trace_some(budget))
+    }
+
+    /// The marking in progress, or a new one.
+    pub fn take_marker(&mut self) -> Marker {
+        self.marking.take().unwrap_or_default()
+    }
+
+    /// Stops keeping track of everything reachable from `value`, which is
+    /// leaving the VM.
+    pub fn release(&mut self, value: &Value) {
+        let mut marker = Marker::default();
+        marker.mark(value);
+        marker.trace();
+        self.objects
+            .retain(|&address, _| !marker.is_marked(address));
+    }
+
+    /// How many objects are being tracked.
+    pub fn objects(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn should_collect(&self) -> bool {
+        match self.stress {
+            true => self.allocated,
+            false => self.bytes >= self.next_gc,
+        }
+    }
+
+    /// Empties the objects the marker couldn't reach from the roots it was
+    /// given, removes the weak map entries whose keys it didn't reach, and
+    /// puts aside the finalizers of the objects it didn't reach. Objects
+    /// that are only held outside the VM count as unreachable.
+    pub fn sweep(&mut self, mut marker: Marker) {
+        marker.trace();
+        #[cfg(debug_assertions)]
+        self.check_marking(&marker);
+
+        let (live, due): (Vec<_>, Vec<_>) = std::mem::take(&mut self.finalizers)
+            .into_iter()
+            .partition(|(object, _)| object.is_alive() && marker.is_marked(object.address()));
+        self.finalizers = live;
+        self.pending_finalizers
+            .extend(due.into_iter().map(|(_, finalizer)| finalizer));
+
+        // Dropped once nothing is borrowed, since it may free more objects.
+        let mut garbage = Vec::new();
+        let mut freed = 0;
+        for (&address, object) in &self.objects {
+            let marked = marker.is_marked(address);
+            match object {
+                Object::List(list) => {
+                    if let Some(list) = list.upgrade().filter(|_| !marked) {
+                        garbage.append(&mut list.borrow_mut());
+                        freed += 1;
+                    }
+                }
+                Object::WeakMap(map) => {
+                    let Some(map) = map.upgrade() else {
+                        continue;
+                    };
+                    let entries = std::mem::take(&mut map.borrow_mut().entries);
+                    let (live, dead) = entries.into_iter().partition(|(key, _)| {
+                        marked && key.is_alive() && marker.is_marked(key.address())
+                    });
+                    map.borrow_mut().entries = live;
+                    garbage.extend(dead.into_iter().map(|(_, value): (_, Value)| value));
+                    freed += !marked as usize;
+                }
+                _ => {}
+            }
+        }
+        drop(garbage);
+
+        // Emptied objects may still be alive if something outside the VM
+        // holds them, so they're only forgotten once they have been freed.
+        let mut bytes = 0;
+        self.objects.retain(|_, object| match object.size() {
+            Some(size) => {
+                bytes += size;
+                true
+            }
+            None => false,
+        });
+        self.bytes = bytes;
+
+        self.stats.collections += 1;
+        self.stats.freed += freed;
+        self.next_gc = (self.bytes * GROWTH_FACTOR).max(INITIAL_THRESHOLD);
+        self.allocated = false;
+    }
+
+    /// Makes sure no list that can still be reached from the roots is about
+    /// to be emptied, which happens when a store skips the write barrier.
+    #[cfg(debug_assertions)]
+    fn check_marking(&self, marker: &Marker) {
+        let reachable = marker.remark();
+        for (&address, object) in &self.objects {
+            if let Object::List(list) = object {
+                debug_assert!(
+                    list.strong_count() == 0
+                        || marker.is_marked(address)
+                        || !reachable.is_marked(address),
+                    "a reachable list wasn't marked"
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::{Rc, Weak};
+
+    use crate::virtual_machine::value::Value;
+
+    use super::{Heap, List, Marker};
+
+    fn list(elements: Vec<Value>) -> (Value, Weak<List>) {
+        let list = Value::new_list(elements);
+        let Value::List(rc) = &list else {
+            unreachable!()
+        };
+        let weak = Rc::downgrade(rc);
+        (list, weak)
+    }
+
+    #[test]
+    fn collects_cycles() {
+        let mut heap = Heap::new(false, None);
+
+        // `a` and `b` refer to each other, and `c` to `a` through a tuple.
+        let (a, a_weak) = list(vec![Value::Nil]);
+        let (b, b_weak) = list(vec![a.clone()]);
+        a.set_index(&Value::Integer(0), b.clone());
+        let (c, c_weak) = list(vec![Value::new_tuple(vec![a.clone()])]);
+        for value in [&a, &b, &c] {
+            heap.track(value);
+        }
+        drop((a, b));
+
+        let mut marker = Marker::default();
+        marker.mark(&c);
+        heap.sweep(marker);
+
+        assert!(a_weak.upgrade().is_some() && b_weak.upgrade().is_some());
+        assert_eq!(heap.objects(), 3);
+
+        drop(c);
+        heap.sweep(Marker::default());
+
+        assert!(a_weak.upgrade().is_none() && b_weak.upgrade().is_none());
+        assert!(c_weak.upgrade().is_none());
+        assert_eq!(heap.objects(), 0);
+        assert_eq!(heap.stats.freed, 2);
+    }
+
+    #[test]
+    fn marks_incrementally() {
+        let mut heap = Heap::new(false, None);
+
+        let (root, _) = list(vec![Value::Nil, Value::Nil]);
+        let (a, a_weak) = list(vec![Value::Integer(1)]);
+        let (b, b_weak) = list(vec![Value::Integer(2)]);
+        for value in [&root, &a, &b] {
+            heap.track(value);
+        }
+        // Only `root` is found at first, with `a` and `b` somewhere the
+        // program can still reach them.
+        let mut marker = Marker::default();
+        marker.mark(&root);
+        heap.start_marking(marker);
+        assert!(heap.mark_step(1));
+
+        // Stores behind the marking's back have to go through the barrier.
+        root.set_index(&Value::Integer(0), a.clone());
+        heap.write_barrier(&a);
+        root.set_index(&Value::Integer(1), b.clone());
+        heap.write_barrier(&b);
+        drop((a, b));
+
+        let marker = heap.take_marker();
+        heap.sweep(marker);
+        assert!(!heap.is_marking());
+        assert_eq!(
+            a_weak.upgrade().map(|a| a.borrow().clone()),
+            Some(vec![Value::Integer(1)])
+        );
+        assert_eq!(
+            b_weak.upgrade().map(|b| b.borrow().clone()),
+            Some(vec![Value::Integer(2)])
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "a reachable list wasn't marked")]
+    fn missed_barriers_are_caught() {
+        let mut heap = Heap::new(false, None);
+
+        let (root, _) = list(vec![Value::Nil]);
+        let (a, _) = list(vec![Value::Integer(1)]);
+        heap.track(&root);
+        heap.track(&a);
+        let mut marker = Marker::default();
+        marker.mark(&root);
+        heap.start_marking(marker);
+        assert!(heap.mark_step(1));
+
+        root.set_index(&Value::Integer(0), a);
+        let marker = heap.take_marker();
+        heap.sweep(marker);
+    }
+
+    #[test]
+    fn released_values_are_left_alone() {
+        let mut heap = Heap::new(true, None);
+
+        let (a, _) = list(vec![Value::Integer(1)]);
+        heap.track(&a);
+        assert!(heap.should_collect());
+
+        heap.release(&a);
+        heap.sweep(Marker::default());
+
+        assert_eq!(a, Value::new_list(vec![Value::Integer(1)]));
+        assert!(!heap.should_collect());
+    }
+}
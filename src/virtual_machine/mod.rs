@@ -1,4 +1,12 @@
+pub mod assembler;
+pub mod binary;
 pub mod chunk;
+pub mod error;
+pub mod function;
+pub mod heap;
+pub mod native;
 pub mod op_code;
 pub mod value;
+pub mod verifier;
 pub mod vm;
+pub mod weak;
@@ -0,0 +1,75 @@
+use std::{cell::RefCell, fmt::Debug, rc::Rc};
+
+use super::{
+    heap::Heap,
+    value::Value,
+    weak::{WeakMap, WeakRef},
+};
+
+/// A function implemented in Rust that scripts can call.
+#[derive(Clone, Copy)]
+pub struct Native {
+    pub name: &'static str,
+    pub arity: usize,
+    pub function: fn(&mut Heap, &[Value]) -> Result<Value, String>,
+}
+
+/// Natives are only equal to themselves.
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Debug for Native {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+/// The natives every script starts with as globals.
+pub const NATIVES: [Native; 3] = [
+    Native {
+        name: "weak",
+        arity: 1,
+        function: weak,
+    },
+    Native {
+        name: "weakmap",
+        arity: 0,
+        function: weakmap,
+    },
+    Native {
+        name: "finalize",
+        arity: 2,
+        function: finalize,
+    },
+];
+
+/// A weak reference to an object, which gives the object back when called,
+/// or `nil` once it has been freed.
+fn weak(_: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    match WeakRef::new(&args[0]) {
+        Some(weak) => Ok(Value::Weak(weak)),
+        None => Err(format!("weak() expects an object, found {}", args[0])),
+    }
+}
+
+fn weakmap(_: &mut Heap, _: &[Value]) -> Result<Value, String> {
+    Ok(Value::WeakMap(Rc::new(RefCell::new(WeakMap::default()))))
+}
+
+/// Has the function called, without arguments, once a collection finds the
+/// object unreachable.
+fn finalize(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let [object, finalizer] = args else {
+        unreachable!("the arity is checked");
+    };
+    if !matches!(finalizer, Value::Function(_)) {
+        return Err(format!("finalize() expects a function, found {finalizer}"));
+    }
+    match heap.add_finalizer(object, finalizer.clone()) {
+        Some(()) => Ok(Value::Nil),
+        None => Err(format!("finalize() expects an object, found {object}")),
+    }
+}
@@ -7,20 +7,353 @@ use num_enum::{IntoPrimitive, TryFromPrimitive};
 pub enum OpCode {
     Return,
     Constant,
+    /// `CONSTANT` with a 24-bit index, for chunks with more than 256
+    /// constants.
+    ConstantLong,
+    Nil,
+    True,
+    False,
+    Pop,
+    GetLocal,
+    SetLocal,
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+    Print,
+    MakeFunction,
+    Call,
+    CallNamed,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    ForIter,
+    Not,
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
     Negate,
     Add,
     Subtract,
     Multiply,
     Divide,
+    FloorDivide,
+    Modulo,
+    Power,
+    BitNot,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+    BuildList,
+    BuildTuple,
+    GetIndex,
+    SetIndex,
+    DuplicatePair,
+    MatchList,
+    MatchTuple,
+    MatchRange,
+    MatchFailed,
+    Slice,
+    BuildVariant,
+    MatchVariant,
+    GetPayload,
+    PushHandler,
+    PushFinally,
+    PopHandler,
+    Throw,
+    EndFinally,
+    Propagate,
+    // The `_LONG` forms of the other instructions with a constant operand,
+    // after the rest so that their numbers stay the same in saved chunks.
+    GetGlobalLong,
+    DefineGlobalLong,
+    SetGlobalLong,
+    MakeFunctionLong,
+    CallNamedLong,
+    BuildVariantLong,
+    MatchVariantLong,
 }
 
 impl OpCode {
     pub const fn num_operands(&self) -> usize {
         match *self {
-            OpCode::Constant => 1,
+            OpCode::Constant
+            | OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::BuildList
+            | OpCode::BuildTuple
+            | OpCode::Call
+            | OpCode::MatchVariant
+            | OpCode::GetPayload => 1,
+            OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::Loop
+            | OpCode::PushHandler
+            | OpCode::PushFinally
+            | OpCode::MatchList
+            | OpCode::MatchTuple
+            | OpCode::Slice
+            | OpCode::BuildVariant
+            | OpCode::MakeFunction
+            | OpCode::CallNamed => 2,
+            OpCode::ForIter
+            | OpCode::ConstantLong
+            | OpCode::GetGlobalLong
+            | OpCode::DefineGlobalLong
+            | OpCode::SetGlobalLong
+            | OpCode::MatchVariantLong => 3,
+            OpCode::MakeFunctionLong | OpCode::CallNamedLong | OpCode::BuildVariantLong => 4,
             _ => 0,
         }
     }
+
+    /// How many values executing the instruction adds to the stack.
+    pub const fn stack_effect(&self, operands: &[u8]) -> isize {
+        match *self {
+            OpCode::BuildList | OpCode::BuildTuple => 1 - operands[0] as isize,
+            // The count comes after the constant index.
+            OpCode::BuildVariant
+            | OpCode::BuildVariantLong
+            | OpCode::MakeFunction
+            | OpCode::MakeFunctionLong => 1 - operands[self.constant_width()] as isize,
+            // The arguments and the callee are replaced by the result.
+            OpCode::Call => -(operands[0] as isize),
+            OpCode::CallNamed | OpCode::CallNamedLong => {
+                -(operands[self.constant_width()] as isize)
+            }
+            OpCode::Constant
+            | OpCode::ConstantLong
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetLocal
+            | OpCode::GetGlobal
+            | OpCode::GetGlobalLong
+            | OpCode::ForIter => 1,
+            OpCode::DuplicatePair => 2,
+            OpCode::SetLocal
+            | OpCode::SetGlobal
+            | OpCode::SetGlobalLong
+            | OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::Loop
+            | OpCode::PushHandler
+            | OpCode::PushFinally
+            | OpCode::PopHandler
+            | OpCode::Propagate
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::BitNot
+            | OpCode::MatchList
+            | OpCode::MatchTuple
+            | OpCode::Slice
+            | OpCode::MatchVariant
+            | OpCode::MatchVariantLong
+            | OpCode::GetPayload => 0,
+            OpCode::Return
+            | OpCode::Pop
+            | OpCode::MatchFailed
+            | OpCode::Throw
+            | OpCode::EndFinally
+            | OpCode::DefineGlobal
+            | OpCode::DefineGlobalLong
+            | OpCode::Print
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::FloorDivide
+            | OpCode::Modulo
+            | OpCode::Power
+            | OpCode::BitAnd
+            | OpCode::BitOr
+            | OpCode::BitXor
+            | OpCode::ShiftLeft
+            | OpCode::ShiftRight
+            | OpCode::GetIndex => -1,
+            OpCode::SetIndex | OpCode::MatchRange => -2,
+        }
+    }
+
+    /// How many values the instruction needs on the stack, whether it pops
+    /// them or only looks at them.
+    pub const fn stack_inputs(&self, operands: &[u8]) -> usize {
+        match *self {
+            OpCode::BuildList | OpCode::BuildTuple => operands[0] as usize,
+            OpCode::BuildVariant
+            | OpCode::BuildVariantLong
+            | OpCode::MakeFunction
+            | OpCode::MakeFunctionLong => operands[self.constant_width()] as usize,
+            OpCode::Call => operands[0] as usize + 1,
+            OpCode::CallNamed | OpCode::CallNamedLong => {
+                operands[self.constant_width()] as usize + 1
+            }
+            OpCode::Constant
+            | OpCode::ConstantLong
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetLocal
+            | OpCode::GetGlobal
+            | OpCode::GetGlobalLong
+            | OpCode::ForIter
+            | OpCode::Jump
+            | OpCode::Loop
+            | OpCode::PushHandler
+            | OpCode::PushFinally
+            | OpCode::PopHandler
+            | OpCode::MatchFailed => 0,
+            OpCode::Return
+            | OpCode::Pop
+            | OpCode::SetLocal
+            | OpCode::DefineGlobal
+            | OpCode::DefineGlobalLong
+            | OpCode::SetGlobal
+            | OpCode::SetGlobalLong
+            | OpCode::Print
+            | OpCode::JumpIfFalse
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::BitNot
+            | OpCode::MatchList
+            | OpCode::MatchTuple
+            | OpCode::Slice
+            | OpCode::MatchVariant
+            | OpCode::MatchVariantLong
+            | OpCode::GetPayload
+            | OpCode::Throw
+            | OpCode::Propagate => 1,
+            // `END_FINALLY` takes how the `try` completed and the value
+            // under it.
+            OpCode::EndFinally
+            | OpCode::DuplicatePair
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::FloorDivide
+            | OpCode::Modulo
+            | OpCode::Power
+            | OpCode::BitAnd
+            | OpCode::BitOr
+            | OpCode::BitXor
+            | OpCode::ShiftLeft
+            | OpCode::ShiftRight
+            | OpCode::GetIndex => 2,
+            OpCode::SetIndex | OpCode::MatchRange => 3,
+        }
+    }
+
+    /// How much fuel executing the instruction uses up. Instructions that
+    /// allocate or set up calls cost more than simple ones.
+    pub const fn cost(&self) -> u64 {
+        match self.short_form() {
+            OpCode::Call | OpCode::CallNamed => 10,
+            OpCode::MakeFunction
+            | OpCode::Throw
+            | OpCode::Propagate
+            | OpCode::PushHandler
+            | OpCode::PushFinally => 5,
+            OpCode::BuildList
+            | OpCode::BuildTuple
+            | OpCode::BuildVariant
+            | OpCode::Slice
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::Power => 3,
+            _ => 1,
+        }
+    }
+
+    /// Jumps and where their 16-bit offset starts among the operands.
+    pub const fn jump_operand(&self) -> Option<usize> {
+        match *self {
+            OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::Loop
+            | OpCode::PushHandler
+            | OpCode::PushFinally => Some(0),
+            OpCode::ForIter => Some(1),
+            _ => None,
+        }
+    }
+
+    /// Whether the first operand is an index into the chunk's constants.
+    pub const fn has_constant_operand(&self) -> bool {
+        self.long_form().is_some() || self.is_long()
+    }
+
+    /// The form of an instruction with a constant operand whose index takes
+    /// 24 bits rather than 8, for chunks with more than 256 constants.
+    pub const fn long_form(&self) -> Option<OpCode> {
+        Some(match *self {
+            OpCode::Constant => OpCode::ConstantLong,
+            OpCode::GetGlobal => OpCode::GetGlobalLong,
+            OpCode::DefineGlobal => OpCode::DefineGlobalLong,
+            OpCode::SetGlobal => OpCode::SetGlobalLong,
+            OpCode::MakeFunction => OpCode::MakeFunctionLong,
+            OpCode::CallNamed => OpCode::CallNamedLong,
+            OpCode::BuildVariant => OpCode::BuildVariantLong,
+            OpCode::MatchVariant => OpCode::MatchVariantLong,
+            _ => return None,
+        })
+    }
+
+    /// The form of a `_LONG` instruction with an 8-bit constant index, which
+    /// works the same way. Other instructions are their own short form.
+    pub const fn short_form(&self) -> OpCode {
+        match *self {
+            OpCode::ConstantLong => OpCode::Constant,
+            OpCode::GetGlobalLong => OpCode::GetGlobal,
+            OpCode::DefineGlobalLong => OpCode::DefineGlobal,
+            OpCode::SetGlobalLong => OpCode::SetGlobal,
+            OpCode::MakeFunctionLong => OpCode::MakeFunction,
+            OpCode::CallNamedLong => OpCode::CallNamed,
+            OpCode::BuildVariantLong => OpCode::BuildVariant,
+            OpCode::MatchVariantLong => OpCode::MatchVariant,
+            op => op,
+        }
+    }
+
+    const fn is_long(&self) -> bool {
+        self.short_form() as u8 != *self as u8
+    }
+
+    /// How many bytes the constant index takes up, if there is one.
+    pub const fn constant_width(&self) -> usize {
+        match (self.is_long(), self.long_form()) {
+            (true, _) => 3,
+            (false, Some(_)) => 1,
+            (false, None) => 0,
+        }
+    }
+
+    /// The index into the chunk's constants among the operands, if there is
+    /// one.
+    pub const fn constant_index(&self, operands: &[u8]) -> Option<usize> {
+        match self.constant_width() {
+            3 => Some(u32::from_be_bytes([0, operands[0], operands[1], operands[2]]) as usize),
+            1 => Some(operands[0] as usize),
+            _ => None,
+        }
+    }
 }
 
 impl Display for OpCode {
@@ -31,11 +364,70 @@ impl Display for OpCode {
             match self {
                 OpCode::Return => "RETURN",
                 OpCode::Constant => "CONSTANT",
+                OpCode::ConstantLong => "CONSTANT_LONG",
+                OpCode::Nil => "NIL",
+                OpCode::True => "TRUE",
+                OpCode::False => "FALSE",
+                OpCode::Pop => "POP",
+                OpCode::GetLocal => "GET_LOCAL",
+                OpCode::SetLocal => "SET_LOCAL",
+                OpCode::GetGlobal => "GET_GLOBAL",
+                OpCode::DefineGlobal => "DEFINE_GLOBAL",
+                OpCode::SetGlobal => "SET_GLOBAL",
+                OpCode::Print => "PRINT",
+                OpCode::MakeFunction => "MAKE_FUNCTION",
+                OpCode::Call => "CALL",
+                OpCode::CallNamed => "CALL_NAMED",
+                OpCode::Jump => "JUMP",
+                OpCode::JumpIfFalse => "JUMP_IF_FALSE",
+                OpCode::Loop => "LOOP",
+                OpCode::ForIter => "FOR_ITER",
+                OpCode::Not => "NOT",
+                OpCode::Equal => "EQUAL",
+                OpCode::Greater => "GREATER",
+                OpCode::GreaterEqual => "GREATER_EQUAL",
+                OpCode::Less => "LESS",
+                OpCode::LessEqual => "LESS_EQUAL",
                 OpCode::Negate => "NEGATE",
                 OpCode::Add => "ADD",
                 OpCode::Subtract => "SUBTRACT",
                 OpCode::Multiply => "MULTIPLY",
                 OpCode::Divide => "DIVIDE",
+                OpCode::FloorDivide => "FLOOR_DIVIDE",
+                OpCode::Modulo => "MODULO",
+                OpCode::Power => "POWER",
+                OpCode::BitNot => "BIT_NOT",
+                OpCode::BitAnd => "BIT_AND",
+                OpCode::BitOr => "BIT_OR",
+                OpCode::BitXor => "BIT_XOR",
+                OpCode::ShiftLeft => "SHIFT_LEFT",
+                OpCode::ShiftRight => "SHIFT_RIGHT",
+                OpCode::BuildList => "BUILD_LIST",
+                OpCode::BuildTuple => "BUILD_TUPLE",
+                OpCode::GetIndex => "GET_INDEX",
+                OpCode::SetIndex => "SET_INDEX",
+                OpCode::DuplicatePair => "DUPLICATE_PAIR",
+                OpCode::MatchList => "MATCH_LIST",
+                OpCode::MatchTuple => "MATCH_TUPLE",
+                OpCode::MatchRange => "MATCH_RANGE",
+                OpCode::MatchFailed => "MATCH_FAILED",
+                OpCode::Slice => "SLICE",
+                OpCode::BuildVariant => "BUILD_VARIANT",
+                OpCode::MatchVariant => "MATCH_VARIANT",
+                OpCode::GetPayload => "GET_PAYLOAD",
+                OpCode::PushHandler => "PUSH_HANDLER",
+                OpCode::PushFinally => "PUSH_FINALLY",
+                OpCode::PopHandler => "POP_HANDLER",
+                OpCode::Throw => "THROW",
+                OpCode::EndFinally => "END_FINALLY",
+                OpCode::Propagate => "PROPAGATE",
+                OpCode::GetGlobalLong => "GET_GLOBAL_LONG",
+                OpCode::DefineGlobalLong => "DEFINE_GLOBAL_LONG",
+                OpCode::SetGlobalLong => "SET_GLOBAL_LONG",
+                OpCode::MakeFunctionLong => "MAKE_FUNCTION_LONG",
+                OpCode::CallNamedLong => "CALL_NAMED_LONG",
+                OpCode::BuildVariantLong => "BUILD_VARIANT_LONG",
+                OpCode::MatchVariantLong => "MATCH_VARIANT_LONG",
             }
         )
     }
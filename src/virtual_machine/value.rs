@@ -1 +1,539 @@
-pub type Value = f64;
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    fmt::{Debug, Display},
+    rc::Rc,
+};
+
+use super::{
+    function::Function,
+    native::Native,
+    weak::{WeakMap, WeakRef},
+};
+
+#[derive(Clone, Default, PartialEq)]
+pub enum Value {
+    #[default]
+    Nil,
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(Rc<str>),
+    List(Rc<RefCell<Vec<Value>>>),
+    /// An immutable, fixed-length sequence.
+    Tuple(Rc<[Value]>),
+    Enum(Rc<EnumValue>),
+    Function(Rc<Function>),
+    Native(Native),
+    Weak(WeakRef),
+    WeakMap(Rc<RefCell<WeakMap>>),
+}
+
+/// One case of an `enum` declaration.
+#[derive(Debug, PartialEq)]
+pub struct Variant {
+    pub enum_name: Rc<str>,
+    pub name: Rc<str>,
+    /// How many values the variant carries.
+    pub arity: u8,
+}
+
+/// The enum every script can use without declaring it.
+pub const RESULT_ENUM: &str = "Result";
+
+impl Variant {
+    /// The variants of the built-in `Result` enum, `Ok(value)` and
+    /// `Err(error)`.
+    pub fn result_variants() -> [Variant; 2] {
+        ["Ok", "Err"].map(|name| Variant {
+            enum_name: RESULT_ENUM.into(),
+            name: name.into(),
+            arity: 1,
+        })
+    }
+}
+
+/// The enum of the errors the VM throws when a script goes over one of its
+/// limits, which scripts can catch and match on.
+pub const RUNTIME_ERROR_ENUM: &str = "RuntimeError";
+
+impl Variant {
+    pub fn runtime_error_variants() -> [Variant; 2] {
+        ["StackOverflow", "OutOfMemory"].map(|name| Variant {
+            enum_name: RUNTIME_ERROR_ENUM.into(),
+            name: name.into(),
+            arity: 0,
+        })
+    }
+}
+
+/// A variant together with the values it carries.
+#[derive(Debug, PartialEq)]
+pub struct EnumValue {
+    pub variant: Rc<Variant>,
+    pub payload: Vec<Value>,
+}
+
+/// Arithmetic returns `None` when the operands have the wrong types or the
+/// result doesn't fit in an integer.
+impl Value {
+    /// What kind of value it is, as error messages call it.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Integer(_) => "integer",
+            Value::Float(_) => "float",
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Tuple(_) => "tuple",
+            Value::Enum(_) => "enum",
+            Value::Function(_) | Value::Native(_) => "function",
+            Value::Weak(_) => "weak reference",
+            Value::WeakMap(_) => "weak map",
+        }
+    }
+
+    fn as_float(&self) -> Option<f64> {
+        match *self {
+            Value::Integer(value) => Some(value as f64),
+            Value::Float(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn arithmetic(
+        &self,
+        rhs: &Value,
+        integer: fn(i64, i64) -> Option<i64>,
+        float: fn(f64, f64) -> f64,
+    ) -> Option<Value> {
+        match (self, rhs) {
+            (Value::Integer(a), Value::Integer(b)) => integer(*a, *b).map(Value::Integer),
+            _ => Some(Value::Float(float(self.as_float()?, rhs.as_float()?))),
+        }
+    }
+
+    /// Bitwise operators are only defined on integers.
+    fn bitwise(&self, rhs: &Value, op: fn(i64, i64) -> Option<i64>) -> Option<Value> {
+        match (self, rhs) {
+            (Value::Integer(a), Value::Integer(b)) => op(*a, *b).map(Value::Integer),
+            _ => None,
+        }
+    }
+
+    /// `nil` and `false` are falsey, everything else is truthy.
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    /// Equality as seen by scripts, where `1 == 1.0`.
+    pub fn equals(&self, rhs: &Value) -> bool {
+        match (self, rhs) {
+            (Value::Integer(_), Value::Float(_)) | (Value::Float(_), Value::Integer(_)) => {
+                self.as_float() == rhs.as_float()
+            }
+            (Value::List(a), Value::List(b)) if Rc::ptr_eq(a, b) => true,
+            (Value::WeakMap(a), Value::WeakMap(b)) => Rc::ptr_eq(a, b),
+            (Value::List(a), Value::List(b)) => {
+                let pair = (Rc::as_ptr(a) as usize, Rc::as_ptr(b) as usize);
+                // Lists that contain themselves are equal if comparing them
+                // gets back to a pair that's already being compared.
+                if COMPARING.with(|comparing| comparing.borrow().contains(&pair)) {
+                    return true;
+                }
+                COMPARING.with(|comparing| comparing.borrow_mut().push(pair));
+                let (a, b) = (a.borrow(), b.borrow());
+                let result = a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a.equals(b));
+                COMPARING.with(|comparing| comparing.borrow_mut().pop());
+                result
+            }
+            (Value::Tuple(a), Value::Tuple(b)) => {
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a.equals(b))
+            }
+            (Value::Enum(a), Value::Enum(b)) => {
+                a.variant == b.variant
+                    && a.payload
+                        .iter()
+                        .zip(b.payload.iter())
+                        .all(|(a, b)| a.equals(b))
+            }
+            _ => self == rhs,
+        }
+    }
+
+    /// Numbers compare with numbers and strings with strings. Comparisons
+    /// involving NaN are false.
+    pub fn compare(&self, rhs: &Value, predicate: fn(Ordering) -> bool) -> Option<Value> {
+        let ordering = match (self, rhs) {
+            (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            _ => self.as_float()?.partial_cmp(&rhs.as_float()?),
+        };
+
+        Some(Value::Bool(ordering.is_some_and(predicate)))
+    }
+
+    /// Whether `lower <= self < upper`, without erroring on values that can't
+    /// be compared.
+    pub fn in_range(&self, lower: &Value, upper: &Value) -> bool {
+        let is = |a: &Value, b: &Value, predicate: fn(Ordering) -> bool| {
+            a.compare(b, predicate) == Some(Value::Bool(true))
+        };
+        is(self, lower, Ordering::is_ge) && is(self, upper, Ordering::is_lt)
+    }
+
+    pub fn new_list(elements: Vec<Value>) -> Value {
+        Value::List(Rc::new(RefCell::new(elements)))
+    }
+
+    pub fn new_tuple(elements: Vec<Value>) -> Value {
+        Value::Tuple(elements.into())
+    }
+
+    /// Calls `f` with the elements of a list or tuple.
+    fn with_elements<T>(&self, f: impl FnOnce(&[Value]) -> T) -> Option<T> {
+        match self {
+            Value::List(list) => Some(f(&list.borrow())),
+            Value::Tuple(tuple) => Some(f(tuple)),
+            _ => None,
+        }
+    }
+
+    /// Lists can be indexed from the back with negative indices.
+    fn list_index(len: usize, index: &Value) -> Option<usize> {
+        let Value::Integer(index) = *index else {
+            return None;
+        };
+        let index = if index < 0 {
+            len.checked_sub(index.unsigned_abs() as usize)?
+        } else {
+            index as usize
+        };
+        (index < len).then_some(index)
+    }
+
+    pub fn get_index(&self, index: &Value) -> Option<Value> {
+        if let Value::WeakMap(map) = self {
+            return map.borrow().get(index);
+        }
+        self.with_elements(|elements| {
+            let index = Value::list_index(elements.len(), index)?;
+            Some(elements[index].clone())
+        })?
+    }
+
+    /// The element at `index` of a list or tuple, or `Some(None)` past its
+    /// end. `None` if this isn't a sequence at all.
+    pub fn element(&self, index: usize) -> Option<Option<Value>> {
+        self.with_elements(|elements| elements.get(index).cloned())
+    }
+
+    pub fn set_index(&self, index: &Value, value: Value) -> Option<()> {
+        match self {
+            Value::List(list) => {
+                let mut list = list.borrow_mut();
+                let index = Value::list_index(list.len(), index)?;
+                list[index] = value;
+                Some(())
+            }
+            Value::WeakMap(map) => map.borrow_mut().insert(index, value),
+            _ => None,
+        }
+    }
+
+    /// Whether this is a list, or a tuple if `tuple` is set, of exactly `len`
+    /// elements, or at least `len` elements if `at_least` is set.
+    pub fn is_sequence_of_length(&self, tuple: bool, len: usize, at_least: bool) -> bool {
+        if matches!(self, Value::Tuple(_)) != tuple {
+            return false;
+        }
+        self.with_elements(|elements| match at_least {
+            true => elements.len() >= len,
+            false => elements.len() == len,
+        })
+        .unwrap_or(false)
+    }
+
+    /// A new list or tuple without the first `front` and last `back` elements.
+    pub fn slice(&self, front: usize, back: usize) -> Option<Value> {
+        let elements = self.with_elements(|elements| {
+            let end = elements.len().checked_sub(back)?;
+            Some(elements.get(front..end)?.to_vec())
+        })??;
+
+        match self {
+            Value::Tuple(_) => Some(Value::new_tuple(elements)),
+            _ => Some(Value::new_list(elements)),
+        }
+    }
+
+    pub fn new_variant(variant: Rc<Variant>, payload: Vec<Value>) -> Value {
+        Value::Enum(Rc::new(EnumValue { variant, payload }))
+    }
+
+    pub fn ok(value: Value) -> Value {
+        let [ok, _] = Variant::result_variants();
+        Value::new_variant(Rc::new(ok), vec![value])
+    }
+
+    pub fn err(error: Value) -> Value {
+        let [_, err] = Variant::result_variants();
+        Value::new_variant(Rc::new(err), vec![error])
+    }
+
+    /// The value of an `Ok` or the error of an `Err`, or `None` if this
+    /// isn't a `Result`.
+    pub fn as_result(&self) -> Option<Result<&Value, &Value>> {
+        match self {
+            Value::Enum(value) if &*value.variant.enum_name == RESULT_ENUM => {
+                let payload = value.payload.first()?;
+                match &*value.variant.name {
+                    "Ok" => Some(Ok(payload)),
+                    _ => Some(Err(payload)),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_variant(&self, variant: &Variant) -> bool {
+        matches!(self, Value::Enum(value) if *value.variant == *variant)
+    }
+
+    pub fn payload(&self, index: usize) -> Option<Value> {
+        match self {
+            Value::Enum(value) => value.payload.get(index).cloned(),
+            _ => None,
+        }
+    }
+
+    pub fn negate(&self) -> Option<Value> {
+        match *self {
+            Value::Integer(value) => value.checked_neg().map(Value::Integer),
+            Value::Float(value) => Some(Value::Float(-value)),
+            _ => None,
+        }
+    }
+
+    pub fn add(&self, rhs: &Value) -> Option<Value> {
+        match (self, rhs) {
+            (Value::String(a), Value::String(b)) => Some(Value::String(format!("{a}{b}").into())),
+            _ => self.arithmetic(rhs, i64::checked_add, |a, b| a + b),
+        }
+    }
+
+    pub fn subtract(&self, rhs: &Value) -> Option<Value> {
+        self.arithmetic(rhs, i64::checked_sub, |a, b| a - b)
+    }
+
+    pub fn multiply(&self, rhs: &Value) -> Option<Value> {
+        self.arithmetic(rhs, i64::checked_mul, |a, b| a * b)
+    }
+
+    /// `/` always produces a float, use `~/` for integer division.
+    pub fn divide(&self, rhs: &Value) -> Option<Value> {
+        Some(Value::Float(self.as_float()? / rhs.as_float()?))
+    }
+
+    /// Division rounded towards negative infinity.
+    pub fn floor_divide(&self, rhs: &Value) -> Option<Value> {
+        self.arithmetic(
+            rhs,
+            |a, b| {
+                let quotient = a.checked_div(b)?;
+                if a % b != 0 && (a < 0) != (b < 0) {
+                    Some(quotient - 1)
+                } else {
+                    Some(quotient)
+                }
+            },
+            |a, b| (a / b).floor(),
+        )
+    }
+
+    /// The remainder of `floor_divide`, so it takes the sign of the divisor.
+    pub fn modulo(&self, rhs: &Value) -> Option<Value> {
+        self.arithmetic(
+            rhs,
+            |a, b| {
+                let remainder = a.checked_rem(b)?;
+                if remainder != 0 && (remainder < 0) != (b < 0) {
+                    Some(remainder + b)
+                } else {
+                    Some(remainder)
+                }
+            },
+            |a, b| {
+                let remainder = a % b;
+                if remainder != 0.0 && (remainder < 0.0) != (b < 0.0) {
+                    remainder + b
+                } else {
+                    remainder
+                }
+            },
+        )
+    }
+
+    /// Integers raised to a non-negative integer power stay integers.
+    pub fn power(&self, rhs: &Value) -> Option<Value> {
+        match (self, rhs) {
+            (Value::Integer(a), Value::Integer(b)) if *b >= 0 => {
+                a.checked_pow(u32::try_from(*b).ok()?).map(Value::Integer)
+            }
+            _ => Some(Value::Float(self.as_float()?.powf(rhs.as_float()?))),
+        }
+    }
+
+    pub fn bit_not(&self) -> Option<Value> {
+        match *self {
+            Value::Integer(value) => Some(Value::Integer(!value)),
+            _ => None,
+        }
+    }
+
+    pub fn bit_and(&self, rhs: &Value) -> Option<Value> {
+        self.bitwise(rhs, |a, b| Some(a & b))
+    }
+
+    pub fn bit_or(&self, rhs: &Value) -> Option<Value> {
+        self.bitwise(rhs, |a, b| Some(a | b))
+    }
+
+    pub fn bit_xor(&self, rhs: &Value) -> Option<Value> {
+        self.bitwise(rhs, |a, b| Some(a ^ b))
+    }
+
+    /// Shifting by a negative amount or by 64 or more bits is an error.
+    pub fn shift_left(&self, rhs: &Value) -> Option<Value> {
+        self.bitwise(rhs, |a, b| a.checked_shl(u32::try_from(b).ok()?))
+    }
+
+    /// An arithmetic shift, so the sign is preserved.
+    pub fn shift_right(&self, rhs: &Value) -> Option<Value> {
+        self.bitwise(rhs, |a, b| a.checked_shr(u32::try_from(b).ok()?))
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Integer(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(value: Vec<Value>) -> Self {
+        Value::new_list(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.into())
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(value) => write!(f, "{value}"),
+            Value::Integer(value) => write!(f, "{value}"),
+            Value::Float(value) => write!(f, "{value:?}"),
+            Value::String(value) => write!(f, "{value}"),
+            Value::List(list) => {
+                let address = Rc::as_ptr(list) as usize;
+                // A list that contains itself is printed as `[...]` the
+                // second time round.
+                if PRINTING.with(|printing| printing.borrow().contains(&address)) {
+                    return write!(f, "[...]");
+                }
+                PRINTING.with(|printing| printing.borrow_mut().push(address));
+                let result = (|| {
+                    write!(f, "[")?;
+                    for (i, element) in list.borrow().iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ", ")?;
+                        }
+                        write!(f, "{element}")?;
+                    }
+                    write!(f, "]")
+                })();
+                PRINTING.with(|printing| printing.borrow_mut().pop());
+                result
+            }
+            Value::Tuple(tuple) => {
+                write!(f, "(")?;
+                for (i, element) in tuple.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{element}")?;
+                }
+                if tuple.len() == 1 {
+                    write!(f, ",")?;
+                }
+                write!(f, ")")
+            }
+            Value::Enum(value) => {
+                write!(f, "{}.{}", value.variant.enum_name, value.variant.name)?;
+                if !value.payload.is_empty() {
+                    write!(f, "(")?;
+                    for (i, element) in value.payload.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ", ")?;
+                        }
+                        write!(f, "{element}")?;
+                    }
+                    write!(f, ")")?;
+                }
+                Ok(())
+            }
+            Value::Function(function) => write!(f, "<fn {}>", function.name),
+            Value::Native(native) => write!(f, "<native fn {}>", native.name),
+            Value::Weak(weak) => match weak.upgrade() {
+                Some(value) => write!(f, "<weak {value}>"),
+                None => write!(f, "<weak>"),
+            },
+            Value::WeakMap(map) => write!(f, "<weak map of {}>", map.borrow().len()),
+        }
+    }
+}
+
+thread_local! {
+    /// The lists being displayed, to stop at cycles.
+    static PRINTING: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+    /// The pairs of lists being compared, to stop at cycles.
+    static COMPARING: RefCell<Vec<(usize, usize)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Lists are shown the way they're displayed, which handles cycles.
+impl Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Nil => write!(f, "Nil"),
+            Value::Bool(value) => f.debug_tuple("Bool").field(value).finish(),
+            Value::Integer(value) => f.debug_tuple("Integer").field(value).finish(),
+            Value::Float(value) => f.debug_tuple("Float").field(value).finish(),
+            Value::String(value) => f.debug_tuple("String").field(value).finish(),
+            Value::List(_) => write!(f, "List({self})"),
+            Value::Tuple(value) => f.debug_tuple("Tuple").field(value).finish(),
+            Value::Enum(value) => f.debug_tuple("Enum").field(value).finish(),
+            Value::Function(value) => f.debug_tuple("Function").field(value).finish(),
+            Value::Native(value) => f.debug_tuple("Native").field(value).finish(),
+            Value::Weak(_) | Value::WeakMap(_) => write!(f, "{self}"),
+        }
+    }
+}
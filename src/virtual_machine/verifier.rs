@@ -0,0 +1,411 @@
+//! Checks bytecode that didn't come straight from the compiler, like a
+//! loaded `.lofc` file, before the VM trusts it.
+//!
+//! Every instruction has to decode, its constants have to exist and be of
+//! the kind it expects, and its jumps have to land on other instructions.
+//! Functions need a parameter if they're variadic, and get as many defaults
+//! as they have parameters with one.
+//! Then the code is followed down every path to make sure each instruction
+//! always sees the same number of values on the stack, that it never takes
+//! more than are there and that locals it uses exist.
+
+use std::rc::Rc;
+
+use thiserror::Error;
+
+use super::{chunk::Chunk, op_code::OpCode, value::Value};
+
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("invalid bytecode in {function} at offset {offset}: {message}")]
+pub struct VerifyError {
+    pub function: Rc<str>,
+    pub offset: usize,
+    pub message: String,
+}
+
+impl Chunk {
+    /// Makes sure the chunk can run as a script, along with the functions
+    /// among its constants.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        Verifier::new(self, "script".into()).verify(1)
+    }
+}
+
+struct Instruction<'a> {
+    op: OpCode,
+    operands: &'a [u8],
+    next: usize,
+}
+
+struct Verifier<'a> {
+    chunk: &'a Chunk,
+    function: Rc<str>,
+    /// The instruction that starts at each offset.
+    instructions: Vec<Option<Instruction<'a>>>,
+}
+
+impl<'a> Verifier<'a> {
+    fn new(chunk: &'a Chunk, function: Rc<str>) -> Self {
+        Self {
+            chunk,
+            function,
+            instructions: Vec::new(),
+        }
+    }
+
+    fn error<T>(&self, offset: usize, message: impl Into<String>) -> Result<T, VerifyError> {
+        Err(VerifyError {
+            function: self.function.clone(),
+            offset,
+            message: message.into(),
+        })
+    }
+
+    /// `depth` is how many values the frame starts with: the function and
+    /// its parameters.
+    fn verify(mut self, depth: usize) -> Result<(), VerifyError> {
+        self.decode()?;
+        for offset in 0..self.instructions.len() {
+            if self.instructions[offset].is_some() {
+                self.check_operands(offset)?;
+            }
+        }
+        self.check_stack(depth)?;
+
+        for constant in &self.chunk.constants {
+            if let Value::Function(function) = constant {
+                let verifier = Verifier::new(&function.chunk, function.name.clone());
+                if function.variadic && function.params.is_empty() {
+                    return verifier.error(0, "variadic without any parameters");
+                }
+                verifier.verify(1 + function.params.len())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(&mut self) -> Result<(), VerifyError> {
+        let code = self.chunk.code.as_slice();
+        self.instructions = (0..code.len()).map(|_| None).collect();
+
+        let mut offset = 0;
+        while offset < code.len() {
+            let Ok(op) = OpCode::try_from(code[offset]) else {
+                return self.error(offset, format!("unknown opcode {}", code[offset]));
+            };
+            let next = offset + 1 + op.num_operands();
+            let Some(operands) = code.get(offset + 1..next) else {
+                return self.error(offset, format!("missing operands for {op}"));
+            };
+            self.instructions[offset] = Some(Instruction { op, operands, next });
+            offset = next;
+        }
+        Ok(())
+    }
+
+    fn instruction(&self, offset: usize) -> &Instruction<'a> {
+        self.instructions[offset]
+            .as_ref()
+            .expect("an instruction starts at the offset")
+    }
+
+    fn check_operands(&self, offset: usize) -> Result<(), VerifyError> {
+        let &Instruction { op, operands, .. } = self.instruction(offset);
+
+        if op.jump_operand().is_some() {
+            self.jump_target(offset)?;
+        }
+        let Some(index) = op.constant_index(operands) else {
+            return Ok(());
+        };
+        let Some(constant) = self.chunk.constants.get(index) else {
+            return self.error(offset, format!("invalid constant index {index}"));
+        };
+
+        let expected = match (op.short_form(), constant) {
+            (OpCode::Constant, _)
+            | (OpCode::GetGlobal | OpCode::SetGlobal | OpCode::DefineGlobal, Value::String(_))
+            | (OpCode::BuildVariant | OpCode::MatchVariant, Value::Enum(_)) => return Ok(()),
+            (OpCode::MakeFunction, Value::Function(function)) => {
+                let expected = function.params.iter().filter(|p| p.has_default).count();
+                let given = operands[op.constant_width()] as usize;
+                if given != expected {
+                    return self.error(
+                        offset,
+                        format!(
+                            "{op} gives {given} defaults to {}, which has {expected}",
+                            function.name
+                        ),
+                    );
+                }
+                return Ok(());
+            }
+            (OpCode::CallNamed, Value::Tuple(names))
+                if names.iter().all(|name| matches!(name, Value::String(_))) =>
+            {
+                if names.len() > operands[op.constant_width()] as usize {
+                    return self.error(offset, "more names than arguments");
+                }
+                return Ok(());
+            }
+            (OpCode::GetGlobal | OpCode::SetGlobal | OpCode::DefineGlobal, _) => "a name",
+            (OpCode::BuildVariant | OpCode::MatchVariant, _) => "a variant",
+            (OpCode::MakeFunction, _) => "a function",
+            _ => "a tuple of names",
+        };
+        self.error(
+            offset,
+            format!("{op} expects {expected}, found constant {index}: {constant}"),
+        )
+    }
+
+    /// Where the jump at `offset` goes, which has to be an instruction.
+    fn jump_target(&self, offset: usize) -> Result<usize, VerifyError> {
+        let &Instruction { op, operands, next } = self.instruction(offset);
+        let Some(at) = op.jump_operand() else {
+            unreachable!("{op} isn't a jump");
+        };
+
+        let jump = u16::from_be_bytes([operands[at], operands[at + 1]]) as usize;
+        let target = match op {
+            OpCode::Loop => next.checked_sub(jump),
+            _ => Some(next + jump),
+        };
+        match target {
+            Some(target) if self.instructions.get(target).is_some_and(Option::is_some) => {
+                Ok(target)
+            }
+            _ => self.error(offset, "jump out of bounds"),
+        }
+    }
+
+    /// Follows every path through the code with the number of values on the
+    /// stack, which has to be the same whichever way an instruction is
+    /// reached.
+    fn check_stack(&self, depth: usize) -> Result<(), VerifyError> {
+        let mut depths = vec![None; self.instructions.len()];
+        if depths.is_empty() {
+            return self.error(0, "reached the end of the code without returning");
+        }
+        depths[0] = Some(depth);
+        let mut pending = vec![0];
+
+        while let Some(offset) = pending.pop() {
+            let depth = depths[offset].expect("pending instructions have a depth");
+            for (target, target_depth) in self.successors(offset, depth)? {
+                if target >= depths.len() {
+                    return self.error(offset, "reached the end of the code without returning");
+                }
+                match depths[target] {
+                    Some(known) if known != target_depth => {
+                        return self.error(
+                            target,
+                            format!(
+                                "reached with {known} and with {target_depth} values on the stack"
+                            ),
+                        )
+                    }
+                    Some(_) => {}
+                    None => {
+                        depths[target] = Some(target_depth);
+                        pending.push(target);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the instruction at `offset` can go next when `depth` values are
+    /// on the stack, and how many values are there then.
+    fn successors(&self, offset: usize, depth: usize) -> Result<Vec<(usize, usize)>, VerifyError> {
+        let &Instruction { op, operands, next } = self.instruction(offset);
+
+        // The function itself is in the frame's first slot, which stays put.
+        if depth < 1 + op.stack_inputs(operands) {
+            return self.error(offset, "stack underflow");
+        }
+        let slot = match op {
+            OpCode::GetLocal | OpCode::SetLocal => Some(operands[0] as usize),
+            // The slot after the sequence holds the index.
+            OpCode::ForIter => Some(operands[0] as usize + 1),
+            _ => None,
+        };
+        if slot.is_some_and(|slot| slot >= depth) {
+            return self.error(offset, "invalid local slot");
+        }
+
+        let after = (depth as isize + op.stack_effect(operands)) as usize;
+        Ok(match op {
+            OpCode::Return | OpCode::Throw | OpCode::MatchFailed => vec![],
+            OpCode::Jump | OpCode::Loop => vec![(self.jump_target(offset)?, after)],
+            OpCode::JumpIfFalse => vec![(next, after), (self.jump_target(offset)?, after)],
+            // Once the sequence runs out, nothing is pushed.
+            OpCode::ForIter => vec![(next, after), (self.jump_target(offset)?, depth)],
+            // Handlers start with the thrown value, and `finally` with how
+            // the `try` completed on top of it.
+            OpCode::PushHandler => vec![(next, after), (self.jump_target(offset)?, depth + 1)],
+            OpCode::PushFinally => vec![(next, after), (self.jump_target(offset)?, depth + 2)],
+            // `END_FINALLY` and `PROPAGATE` may also leave the function.
+            _ => vec![(next, after)],
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::virtual_machine::{
+        chunk::Chunk,
+        function::{Function, Parameter},
+        op_code::OpCode,
+        value::Value,
+    };
+
+    fn verify(code: &[u8]) -> Result<(), String> {
+        verify_with(Vec::new(), code)
+    }
+
+    /// Verifies `code` with `constants` after the two every chunk has.
+    fn verify_with(constants: Vec<Value>, code: &[u8]) -> Result<(), String> {
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Value::Integer(1));
+        chunk.add_constant(Value::new_tuple(vec![Value::String("a".into())]));
+        for constant in constants {
+            chunk.add_constant(constant);
+        }
+        for &byte in code {
+            chunk.write_operand(byte, 1, 1);
+        }
+        chunk.verify().map_err(|err| err.message)
+    }
+
+    /// `fn f(a = ...) {}`, or `fn f(..) {}` if it's variadic.
+    fn function(variadic: bool) -> Value {
+        let mut chunk = Chunk::new();
+        chunk.write_operation(OpCode::Nil, [], 1, 1);
+        chunk.write_operation(OpCode::Return, [], 1, 1);
+        let params = match variadic {
+            true => Vec::new(),
+            false => vec![Parameter {
+                name: Some("a".into()),
+                has_default: true,
+            }],
+        };
+        Value::Function(Rc::new(Function {
+            name: "f".into(),
+            chunk: Rc::new(chunk),
+            params: params.into(),
+            variadic,
+            defaults: Vec::new(),
+        }))
+    }
+
+    fn code(ops: &[(OpCode, &[u8])]) -> Vec<u8> {
+        ops.iter()
+            .flat_map(|&(op, operands)| [&[op.into()], operands].concat())
+            .collect()
+    }
+
+    #[test]
+    fn accepts_valid_code() {
+        assert_eq!(
+            verify(&code(&[
+                (OpCode::Constant, &[0]),
+                (OpCode::JumpIfFalse, &[0, 5]),
+                (OpCode::Pop, &[]),
+                (OpCode::Nil, &[]),
+                (OpCode::Jump, &[0, 0]),
+                (OpCode::Return, &[]),
+            ])),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_code() {
+        for (code, message) in [
+            (vec![255], "unknown opcode 255"),
+            (
+                code(&[(OpCode::Nil, &[])]),
+                "reached the end of the code without returning",
+            ),
+            (
+                vec![OpCode::Constant.into()],
+                "missing operands for CONSTANT",
+            ),
+            (
+                code(&[(OpCode::Constant, &[7])]),
+                "invalid constant index 7",
+            ),
+            (
+                code(&[(OpCode::Pop, &[]), (OpCode::Return, &[])]),
+                "stack underflow",
+            ),
+            (code(&[(OpCode::Add, &[])]), "stack underflow"),
+            (
+                code(&[(OpCode::GetLocal, &[1]), (OpCode::Return, &[])]),
+                "invalid local slot",
+            ),
+            (code(&[(OpCode::Loop, &[0, 9])]), "jump out of bounds"),
+            (
+                code(&[
+                    (OpCode::Jump, &[0, 1]),
+                    (OpCode::Constant, &[0]),
+                    (OpCode::Return, &[]),
+                ]),
+                "jump out of bounds",
+            ),
+            (
+                code(&[(OpCode::GetGlobal, &[0]), (OpCode::Return, &[])]),
+                "GET_GLOBAL expects a name, found constant 0: 1",
+            ),
+            (
+                code(&[
+                    (OpCode::Nil, &[]),
+                    (OpCode::CallNamed, &[1, 0]),
+                    (OpCode::Return, &[]),
+                ]),
+                "more names than arguments",
+            ),
+            (
+                code(&[
+                    (OpCode::True, &[]),
+                    (OpCode::JumpIfFalse, &[0, 1]),
+                    (OpCode::Nil, &[]),
+                    (OpCode::Return, &[]),
+                ]),
+                "reached with 2 and with 3 values on the stack",
+            ),
+        ] {
+            assert_eq!(verify(&code), Err(message.into()), "{code:?}");
+        }
+
+        let make_function = |defaults| {
+            code(&[
+                (OpCode::Nil, &[]),
+                (OpCode::MakeFunction, &[2, defaults]),
+                (OpCode::Return, &[]),
+            ])
+        };
+        assert_eq!(
+            verify_with(vec![function(false)], &make_function(1)),
+            Ok(())
+        );
+        for (constant, code, message) in [
+            (
+                function(false),
+                make_function(0),
+                "MAKE_FUNCTION gives 0 defaults to f, which has 1",
+            ),
+            (
+                function(true),
+                code(&[(OpCode::Nil, &[]), (OpCode::Return, &[])]),
+                "variadic without any parameters",
+            ),
+        ] {
+            assert_eq!(verify_with(vec![constant], &code), Err(message.into()));
+        }
+    }
+}
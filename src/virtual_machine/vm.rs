@@ -1,110 +1,960 @@
-use std::ops::{Add, Div, Mul, Sub};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt::Display,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+        Arc,
+    },
+    time::Instant,
+};
 
 use super::{
     chunk::{disassemble_operation, Chunk},
+    error::{ErrorKind, RuntimeError, TraceFrame},
+    function::Function,
+    heap::{GcMode, Heap, Marker, WEAK_MAP_ENTRY},
+    native::{Native, NATIVES},
     op_code::OpCode,
     value::Value,
 };
 
 const DEBUG_TRACE_EXECUTION: bool = true;
-const STACK_MAX: usize = 256;
+
+/// How a `try` with a `finally` was left, which `END_FINALLY` resumes once
+/// the `finally` block has run. The compiler pushes `COMPLETED` itself.
+const COMPLETED: i64 = 0;
+const THREW: i64 = 1;
+const RETURNED: i64 = 2;
+
+/// Where to continue when a value is thrown.
+struct Handler {
+    /// How many frames were on the call stack, the handler's own frame
+    /// being the last.
+    frames: usize,
+    stack_len: usize,
+    target: usize,
+    finally: bool,
+}
+
+struct CallFrame {
+    function: Rc<Function>,
+    instruction_pointer: usize,
+    /// Where the frame's locals start on the stack. The first slot holds
+    /// the function being called.
+    slots: usize,
+    /// Whether the frame is running a finalizer, which returns nothing to
+    /// the code it interrupted.
+    finalizer: bool,
+}
+
+/// Limits on the resources a script may use.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// How many values the stack may hold. It starts small and grows up to
+    /// this.
+    pub max_stack: usize,
+    /// How deep calls may nest.
+    pub max_frames: usize,
+    pub gc_mode: GcMode,
+    /// Collect garbage after every allocation.
+    pub stress_gc: bool,
+    /// How many bytes the heap may use. Going over throws an error.
+    pub max_heap: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_stack: 1 << 16,
+            max_frames: 256,
+            gc_mode: GcMode::StopTheWorld,
+            stress_gc: false,
+            max_heap: None,
+        }
+    }
+}
+
+/// Stops a VM from another thread. The script is stopped at its next call
+/// or backward jump, so loops and recursion can't run on forever.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.flag.store(true, AtomicOrdering::Relaxed);
+    }
+
+    /// Clears the request, returning whether there was one.
+    fn take(&self) -> bool {
+        self.flag.swap(false, AtomicOrdering::Relaxed)
+    }
+}
 
 pub struct VM {
-    pub chunk: Chunk,
-    pub instruction_pointer: usize,
-    pub stack: [Value; STACK_MAX],
-    pub stack_top: usize,
+    config: Config,
+    frames: Vec<CallFrame>,
+    handlers: Vec<Handler>,
+    /// The value being thrown while the VM unwinds.
+    thrown: Option<Value>,
+    pub stack: Vec<Value>,
+    pub globals: HashMap<Rc<str>, Value>,
+    pub heap: Heap,
+    /// How much fuel is left, if it's limited.
+    fuel: Option<u64>,
+    interrupt: InterruptHandle,
 }
 
-type InterpretResult = Result<Value, ()>;
+type InterpretResult = Result<Value, RuntimeError>;
+
+/// Where running with limited fuel got to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunState {
+    Finished(Value),
+    /// The next instruction costs more fuel than is left. Adding fuel and
+    /// resuming carries on from there.
+    OutOfFuel,
+}
 
 impl VM {
     pub fn new(chunk: Chunk) -> Self {
-        Self {
-            chunk,
+        Self::with_config(chunk, Config::default())
+    }
+
+    pub fn with_config(chunk: Chunk, config: Config) -> Self {
+        let mut vm = Self {
+            heap: Heap::new(config.stress_gc, config.max_heap),
+            config,
+            frames: Vec::new(),
+            handlers: Vec::new(),
+            thrown: None,
+            stack: Vec::with_capacity(256),
+            fuel: None,
+            interrupt: InterruptHandle::default(),
+            globals: NATIVES
+                .iter()
+                .map(|native| (native.name.into(), Value::Native(*native)))
+                .collect(),
+        };
+        vm.load(chunk);
+        vm
+    }
+
+    /// Sets the VM up to run `chunk` as the top level of a script.
+    fn load(&mut self, chunk: Chunk) {
+        let script = Rc::new(Function::script(chunk));
+
+        self.frames.clear();
+        self.handlers.clear();
+        self.stack.clear();
+        self.stack.push(Value::Function(script.clone()));
+        self.frames.push(CallFrame {
+            function: script,
             instruction_pointer: 0,
-            stack: [0.0; STACK_MAX],
-            stack_top: 0,
-        }
+            slots: 0,
+            finalizer: false,
+        });
     }
 
     pub fn interpret(&mut self, chunk: Chunk) -> InterpretResult {
-        self.chunk = chunk;
-        self.instruction_pointer = 0;
+        self.load(chunk);
 
         self.run()
     }
 
     pub fn run(&mut self) -> InterpretResult {
+        self.fuel = None;
+        match self.resume()? {
+            RunState::Finished(result) => Ok(result),
+            RunState::OutOfFuel => unreachable!("fuel is unlimited"),
+        }
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// Runs until the script finishes or `fuel` runs out.
+    pub fn run_with_fuel(&mut self, fuel: u64) -> Result<RunState, RuntimeError> {
+        self.fuel = Some(fuel);
+        self.resume()
+    }
+
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(left) = &mut self.fuel {
+            *left = left.saturating_add(fuel);
+        }
+    }
+
+    /// How much fuel is left, or `None` if it isn't limited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Carries on running where the VM stopped, with the fuel that's left.
+    pub fn resume(&mut self) -> Result<RunState, RuntimeError> {
+        let Some(frame) = self.frames.last() else {
+            return Err(RuntimeError {
+                kind: ErrorKind::Uncaught,
+                message: "no script is loaded".to_owned(),
+                line: 0,
+                column: 0,
+                trace: Vec::new(),
+            });
+        };
         if DEBUG_TRACE_EXECUTION {
-            print!("\n{:=^50}\n", self.chunk.name.unwrap_or(""));
+            println!(
+                "\n{:=^50}",
+                frame.function.chunk.name.as_deref().unwrap_or("")
+            );
         }
 
         loop {
+            if self.heap.is_marking() || self.heap.should_collect() {
+                self.step_garbage_collector();
+            }
+            self.start_finalizers();
+
+            let offset = self.frame().instruction_pointer;
             let instruction = self.read_op();
+            if let (Ok(instruction), Some(fuel)) = (instruction, &mut self.fuel) {
+                let Some(left) = fuel.checked_sub(instruction.cost()) else {
+                    self.frame_mut().instruction_pointer = offset;
+                    return Ok(RunState::OutOfFuel);
+                };
+                *fuel = left;
+            }
+            if matches!(
+                instruction,
+                Ok(OpCode::Loop | OpCode::Call | OpCode::CallNamed | OpCode::CallNamedLong)
+            ) && self.interrupt.take()
+            {
+                return Err(self.abort(ErrorKind::Interrupted, "interrupted".to_owned(), offset));
+            }
+            let result = instruction.and_then(|instruction| {
+                if DEBUG_TRACE_EXECUTION {
+                    println!(
+                        "{}",
+                        disassemble_operation(&self.frame().function.chunk, offset)
+                    );
+                }
+                self.execute(instruction)
+            });
+
+            match result {
+                Ok(None) => {}
+                Ok(Some(result)) => {
+                    self.heap.release(&result);
+                    return Ok(RunState::Finished(result));
+                }
+                Err(()) => {
+                    // Operations on values of the wrong type fail without
+                    // setting an error of their own.
+                    let thrown = self.thrown.take().unwrap_or_else(|| {
+                        let message = match instruction {
+                            Ok(instruction) => format!("invalid operands for {instruction}"),
+                            Err(()) => "invalid instruction".to_owned(),
+                        };
+                        Value::from(message.as_str())
+                    });
+                    if let Err(thrown) = self.unwind(thrown) {
+                        let kind = ErrorKind::of_thrown(&thrown);
+                        let message = RuntimeError::uncaught_message(&thrown);
+                        return Err(self.abort(kind, message, offset));
+                    }
+                }
+            }
 
             if DEBUG_TRACE_EXECUTION {
-                print!(
-                    "{}\n",
-                    disassemble_operation(&self.chunk, self.instruction_pointer - 1),
-                );
+                println!("{:?}", &self.stack);
             }
+        }
+    }
 
-            match instruction {
-                OpCode::Return => {
-                    let val = self.pop();
-                    return Ok(val);
+    /// Returns the script's result once its top level returns.
+    fn execute(&mut self, instruction: OpCode) -> Result<Option<Value>, ()> {
+        match instruction {
+            OpCode::Return => {
+                let result = self.pop()?;
+                return Ok(self.return_from_frame(result));
+            }
+            OpCode::Constant | OpCode::ConstantLong => {
+                let constant = self.read_constant(instruction)?;
+                self.push(constant)?;
+            }
+            OpCode::Nil => self.push(Value::Nil)?,
+            OpCode::True => self.push(Value::Bool(true))?,
+            OpCode::False => self.push(Value::Bool(false))?,
+            OpCode::Pop => {
+                self.pop()?;
+            }
+            OpCode::GetLocal => {
+                let slot = self.read_slot();
+                let value = self.local(slot)?.clone();
+                self.push(value)?;
+            }
+            OpCode::SetLocal => {
+                let slot = self.read_slot();
+                let value = self.peek(0)?.clone();
+                *self.local(slot)? = value;
+            }
+            OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                let name = self.read_string(instruction)?;
+                let Some(value) = self.globals.get(&name).cloned() else {
+                    return self.error(format!("undefined variable '{name}'"));
+                };
+                self.push(value)?;
+            }
+            OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                let name = self.read_string(instruction)?;
+                let value = self.pop()?;
+                self.heap.write_barrier(&value);
+                self.globals.insert(name, value);
+            }
+            OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                let name = self.read_string(instruction)?;
+                let value = self.peek(0)?.clone();
+                let Some(global) = self.globals.get_mut(&name) else {
+                    return self.error(format!("undefined variable '{name}'"));
+                };
+                self.heap.write_barrier(&value);
+                *global = value;
+            }
+            OpCode::Print => println!("{}", self.pop()?),
+            OpCode::MakeFunction | OpCode::MakeFunctionLong => {
+                let Value::Function(prototype) = self.read_constant(instruction)? else {
+                    return Err(());
+                };
+                let defaults = self.read_operand() as usize;
+                let function = Function {
+                    defaults: self.pop_n(defaults)?,
+                    ..Function::clone(&prototype)
+                };
+                self.allocate(Value::Function(Rc::new(function)))?;
+            }
+            OpCode::Call => {
+                let argc = self.read_operand() as usize;
+                self.call(argc, &[])?;
+            }
+            OpCode::CallNamed | OpCode::CallNamedLong => {
+                let Value::Tuple(names) = self.read_constant(instruction)? else {
+                    return Err(());
+                };
+                let names: Vec<Rc<str>> = names
+                    .iter()
+                    .filter_map(|name| match name {
+                        Value::String(name) => Some(name.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                let argc = self.read_operand() as usize;
+                self.call(argc, &names)?;
+            }
+            OpCode::Jump => {
+                let offset = self.read_u16();
+                self.frame_mut().instruction_pointer += offset as usize;
+            }
+            OpCode::JumpIfFalse => {
+                let offset = self.read_u16();
+                if self.peek(0)?.is_falsey() {
+                    self.frame_mut().instruction_pointer += offset as usize;
                 }
-                OpCode::Constant => {
-                    let constant = self.read_constant();
-                    self.push(constant);
+            }
+            OpCode::Loop => {
+                let offset = self.read_u16() as usize;
+                let frame = self.frame_mut();
+                let Some(target) = frame.instruction_pointer.checked_sub(offset) else {
+                    return self.error("jump out of bounds");
+                };
+                frame.instruction_pointer = target;
+            }
+            OpCode::ForIter => {
+                // The sequence's slot is followed by the index of the
+                // next element.
+                let slot = self.read_slot();
+                let offset = self.read_u16();
+                let Value::Integer(index) = *self.local(slot + 1)? else {
+                    return Err(());
+                };
+                match self.local(slot)?.element(index as usize).ok_or(())? {
+                    Some(element) => {
+                        *self.local(slot + 1)? = Value::Integer(index + 1);
+                        self.push(element)?;
+                    }
+                    None => self.frame_mut().instruction_pointer += offset as usize,
                 }
-                OpCode::Negate => {
-                    let val = -self.pop();
-                    self.push(val);
+            }
+            OpCode::Not => {
+                let val = self.pop()?.is_falsey();
+                self.push(Value::Bool(val))?;
+            }
+            OpCode::Equal => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(Value::Bool(a.equals(&b)))?;
+            }
+            OpCode::Greater => self.binary_op(|a, b| a.compare(b, Ordering::is_gt))?,
+            OpCode::GreaterEqual => self.binary_op(|a, b| a.compare(b, Ordering::is_ge))?,
+            OpCode::Less => self.binary_op(|a, b| a.compare(b, Ordering::is_lt))?,
+            OpCode::LessEqual => self.binary_op(|a, b| a.compare(b, Ordering::is_le))?,
+            OpCode::Negate => {
+                let val = self.pop()?.negate().ok_or(())?;
+                self.push(val)?;
+            }
+            OpCode::Add => self.binary_op(Value::add)?,
+            OpCode::Subtract => self.binary_op(Value::subtract)?,
+            OpCode::Multiply => self.binary_op(Value::multiply)?,
+            OpCode::Divide => self.binary_op(Value::divide)?,
+            OpCode::FloorDivide => self.binary_op(Value::floor_divide)?,
+            OpCode::Modulo => self.binary_op(Value::modulo)?,
+            OpCode::Power => self.binary_op(Value::power)?,
+            OpCode::BitNot => {
+                self.expect_integer(0)?;
+                let val = self.pop()?.bit_not().ok_or(())?;
+                self.push(val)?;
+            }
+            OpCode::BitAnd => self.bitwise_op(Value::bit_and)?,
+            OpCode::BitOr => self.bitwise_op(Value::bit_or)?,
+            OpCode::BitXor => self.bitwise_op(Value::bit_xor)?,
+            OpCode::ShiftLeft => self.bitwise_op(Value::shift_left)?,
+            OpCode::ShiftRight => self.bitwise_op(Value::shift_right)?,
+            OpCode::BuildList => {
+                let len = self.read_operand() as usize;
+                let elements = self.pop_n(len)?;
+                self.allocate(Value::new_list(elements))?;
+            }
+            OpCode::BuildTuple => {
+                let len = self.read_operand() as usize;
+                let elements = self.pop_n(len)?;
+                self.allocate(Value::new_tuple(elements))?;
+            }
+            OpCode::GetIndex => self.binary_op(Value::get_index)?,
+            OpCode::SetIndex => {
+                let value = self.pop()?;
+                let index = self.pop()?;
+                let target = self.pop()?;
+                target.set_index(&index, value.clone()).ok_or(())?;
+                self.heap.write_barrier(&value);
+                if let Value::WeakMap(_) = target {
+                    self.heap.account(WEAK_MAP_ENTRY);
                 }
-                OpCode::Add => self.binary_op(Add::add),
-                OpCode::Subtract => self.binary_op(Sub::sub),
-                OpCode::Multiply => self.binary_op(Mul::mul),
-                OpCode::Divide => self.binary_op(Div::div),
+                self.push(value)?;
+            }
+            OpCode::DuplicatePair => {
+                let a = self.peek(1)?.clone();
+                let b = self.peek(0)?.clone();
+                self.push(a)?;
+                self.push(b)?;
             }
+            OpCode::MatchList | OpCode::MatchTuple => {
+                let len = self.read_operand() as usize;
+                let at_least = self.read_operand() != 0;
+                let tuple = instruction == OpCode::MatchTuple;
+                let matches = self.pop()?.is_sequence_of_length(tuple, len, at_least);
+                self.push(Value::Bool(matches))?;
+            }
+            OpCode::MatchFailed => return self.error("value doesn't match the pattern"),
+            OpCode::MatchRange => {
+                let upper = self.pop()?;
+                let lower = self.pop()?;
+                let matches = self.pop()?.in_range(&lower, &upper);
+                self.push(Value::Bool(matches))?;
+            }
+            OpCode::BuildVariant | OpCode::BuildVariantLong => {
+                let Value::Enum(template) = self.read_constant(instruction)? else {
+                    return Err(());
+                };
+                let arity = self.read_operand() as usize;
+                let payload = self.pop_n(arity)?;
+                self.allocate(Value::new_variant(template.variant.clone(), payload))?;
+            }
+            OpCode::MatchVariant | OpCode::MatchVariantLong => {
+                let Value::Enum(template) = self.read_constant(instruction)? else {
+                    return Err(());
+                };
+                let matches = self.pop()?.is_variant(&template.variant);
+                self.push(Value::Bool(matches))?;
+            }
+            OpCode::GetPayload => {
+                let index = self.read_operand() as usize;
+                let value = self.pop()?.payload(index).ok_or(())?;
+                self.push(value)?;
+            }
+            OpCode::Slice => {
+                let front = self.read_operand() as usize;
+                let back = self.read_operand() as usize;
+                let slice = self.pop()?.slice(front, back).ok_or(())?;
+                self.allocate(slice)?;
+            }
+            OpCode::PushHandler | OpCode::PushFinally => {
+                let offset = self.read_u16() as usize;
+                self.handlers.push(Handler {
+                    frames: self.frames.len(),
+                    stack_len: self.stack.len(),
+                    target: self.frame().instruction_pointer + offset,
+                    finally: instruction == OpCode::PushFinally,
+                });
+            }
+            OpCode::PopHandler => {
+                self.handlers.pop();
+            }
+            OpCode::Throw => {
+                let thrown = self.pop()?;
+                self.thrown = Some(thrown);
+                return Err(());
+            }
+            OpCode::Propagate => {
+                let result = self.pop()?;
+                match result.as_result() {
+                    Some(Ok(value)) => self.push(value.clone())?,
+                    Some(Err(_)) => return Ok(self.return_from_frame(result)),
+                    None => return self.error(format!("? expects a Result, found {result}")),
+                }
+            }
+            OpCode::EndFinally => {
+                let Value::Integer(completion) = self.pop()? else {
+                    return Err(());
+                };
+                match completion {
+                    COMPLETED => {}
+                    THREW => {
+                        let thrown = self.pop()?;
+                        self.thrown = Some(thrown);
+                        return Err(());
+                    }
+                    _ => {
+                        let result = self.pop()?;
+                        return Ok(self.return_from_frame(result));
+                    }
+                }
+            }
+        }
 
-            if DEBUG_TRACE_EXECUTION {
-                print!("{:?}\n", &self.stack[0..self.stack_top]);
+        Ok(None)
+    }
+
+    /// Returns from the running frame, unless a `finally` in it has to run
+    /// first. Returns the result if this ends the script.
+    fn return_from_frame(&mut self, result: Value) -> Option<Value> {
+        while let Some(handler) = self.handlers.last() {
+            if handler.frames != self.frames.len() {
+                break;
+            }
+            let handler = self.handlers.pop().expect("there is a handler");
+            if handler.finally {
+                self.enter_handler(handler, result, Some(RETURNED));
+                return None;
+            }
+        }
+
+        let frame = self.frames.pop().expect("a frame is running");
+        self.stack.truncate(frame.slots);
+
+        if frame.finalizer {
+            return None;
+        }
+        if self.frames.is_empty() {
+            return Some(result);
+        }
+        self.stack.push(result);
+        None
+    }
+
+    /// Transfers control to the innermost handler, or gives the thrown value
+    /// back if nothing catches it.
+    /// Errors that escape a finalizer are dropped, rather than thrown into
+    /// the code it interrupted.
+    fn unwind(&mut self, thrown: Value) -> Result<(), Value> {
+        if let Some(finalizer) = self.frames.iter().rposition(|frame| frame.finalizer) {
+            if self
+                .handlers
+                .last()
+                .is_none_or(|handler| handler.frames <= finalizer)
+            {
+                self.stack.truncate(self.frames[finalizer].slots);
+                self.frames.truncate(finalizer);
+                return Ok(());
+            }
+        }
+        let Some(handler) = self.handlers.pop() else {
+            return Err(thrown);
+        };
+
+        let completion = handler.finally.then_some(THREW);
+        self.enter_handler(handler, thrown, completion);
+        Ok(())
+    }
+
+    /// Jumps to a handler with `value` on the stack, followed by how the
+    /// `try` completed if the handler is a `finally`.
+    fn enter_handler(&mut self, handler: Handler, value: Value, completion: Option<i64>) {
+        self.frames.truncate(handler.frames);
+        self.stack.truncate(handler.stack_len);
+        self.stack.push(value);
+        if let Some(completion) = completion {
+            self.stack.push(Value::Integer(completion));
+        }
+        self.frame_mut().instruction_pointer = handler.target;
+    }
+
+    /// Pushes a value that was just made, for the heap to keep track of.
+    fn allocate(&mut self, value: Value) -> Result<(), ()> {
+        self.heap.track(&value);
+        self.push(value)?;
+        self.check_heap_limit()
+    }
+
+    /// Throws if the heap has grown past its limit, even after collecting
+    /// garbage. The value that was allocated last, on top of the stack, is
+    /// given up.
+    fn check_heap_limit(&mut self) -> Result<(), ()> {
+        if !self.heap.over_limit() {
+            return Ok(());
+        }
+        // Whatever was freed since the last collection still counts.
+        let start = Instant::now();
+        self.collect_garbage();
+        self.heap.stats.record_pause(start.elapsed());
+        if self.heap.over_limit() {
+            let value = self.pop()?;
+            self.heap.untrack(&value);
+            return self.fail(ErrorKind::OutOfMemory);
+        }
+        self.heap.account(0);
+        Ok(())
+    }
+
+    /// Empties the lists that can't be reached from the stack, the
+    /// running functions or the globals, which frees their cycles.
+    /// Collections only happen between instructions, when every value in
+    /// use is somewhere the collector can find it.
+    ///
+    /// There are no closures yet, so no upvalues to look at, and constants
+    /// are reached through the functions whose chunks hold them.
+    pub fn collect_garbage(&mut self) {
+        // Finishing an incremental collection marks the roots again, since
+        // the stack isn't behind a write barrier.
+        let mut marker = self.heap.take_marker();
+        self.mark_roots(&mut marker);
+        self.heap.sweep(marker);
+    }
+
+    /// Does the next bit of garbage collection, and times how long the
+    /// program had to wait for it.
+    fn step_garbage_collector(&mut self) {
+        let start = Instant::now();
+        match self.config.gc_mode {
+            GcMode::StopTheWorld => self.collect_garbage(),
+            GcMode::Incremental { budget } => {
+                if !self.heap.is_marking() {
+                    let mut marker = Marker::default();
+                    self.mark_roots(&mut marker);
+                    self.heap.start_marking(marker);
+                } else if self.heap.mark_step(budget) {
+                    self.collect_garbage();
+                }
+            }
+        }
+        self.heap.stats.record_pause(start.elapsed());
+    }
+
+    /// Calls the finalizers the collector found due, one frame on top of
+    /// the other, so the first is called first. They run in between the
+    /// instructions of the code they interrupt, which then carries on.
+    fn start_finalizers(&mut self) {
+        for finalizer in self.heap.take_pending_finalizers().into_iter().rev() {
+            let stack_len = self.stack.len();
+            self.stack.push(finalizer);
+            match self.call(0, &[]) {
+                Ok(()) => self.frame_mut().finalizer = true,
+                Err(()) => {
+                    self.thrown = None;
+                    self.stack.truncate(stack_len);
+                }
+            }
+        }
+    }
+
+    fn mark_roots(&self, marker: &mut Marker) {
+        for value in self
+            .stack
+            .iter()
+            .chain(self.globals.values())
+            .chain(self.heap.finalizers())
+        {
+            marker.mark(value);
+        }
+        if let Some(thrown) = &self.thrown {
+            marker.mark(thrown);
+        }
+        for frame in &self.frames {
+            marker.mark_function(&frame.function);
+        }
+    }
+
+    fn push(&mut self, value: Value) -> Result<(), ()> {
+        if self.stack.len() >= self.config.max_stack {
+            return self.fail(ErrorKind::StackOverflow);
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<Value, ()> {
+        self.check_underflow(1)?;
+        self.stack
+            .pop()
+            .map_or_else(|| self.error("stack underflow"), Ok)
+    }
+
+    /// Pops the top `n` values, in the order they were pushed.
+    fn pop_n(&mut self, n: usize) -> Result<Vec<Value>, ()> {
+        self.check_underflow(n)?;
+        let Some(start) = self.stack.len().checked_sub(n) else {
+            return self.error("stack underflow");
+        };
+        Ok(self.stack.split_off(start))
+    }
+
+    /// In debug builds, makes sure popping `n` values leaves the running
+    /// frame's slots alone, which only broken bytecode does.
+    fn check_underflow(&mut self, n: usize) -> Result<(), ()> {
+        if cfg!(debug_assertions) && self.stack.len() < self.frame().slots + 1 + n {
+            return self.error("stack underflow");
+        }
+        Ok(())
+    }
+
+    fn peek(&mut self, distance: usize) -> Result<&Value, ()> {
+        if distance >= self.stack.len() {
+            return self.error("stack underflow");
+        }
+        Ok(&self.stack[self.stack.len() - 1 - distance])
+    }
+
+    /// The value in an absolute stack slot, which has to be in use.
+    fn local(&mut self, slot: usize) -> Result<&mut Value, ()> {
+        if slot >= self.stack.len() {
+            return self.error("invalid local slot");
+        }
+        Ok(&mut self.stack[slot])
+    }
+
+    fn binary_op(&mut self, op: fn(&Value, &Value) -> Option<Value>) -> Result<(), ()> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+
+        self.allocate(op(&a, &b).ok_or(())?)
+    }
+
+    fn bitwise_op(&mut self, op: fn(&Value, &Value) -> Option<Value>) -> Result<(), ()> {
+        self.expect_integer(1)?;
+        self.expect_integer(0)?;
+        self.binary_op(op)
+    }
+
+    /// Throws unless the operand `distance` down the stack is an integer,
+    /// which bitwise operators need.
+    fn expect_integer(&mut self, distance: usize) -> Result<(), ()> {
+        let operand = self.peek(distance)?;
+        if matches!(operand, Value::Integer(_)) {
+            return Ok(());
+        }
+        let message = format!(
+            "bitwise operands must be integers, found {}",
+            operand.type_name()
+        );
+        self.error(message)
+    }
+
+    /// Calls the function below the top `argc` values, of which the last
+    /// `names.len()` were passed by name.
+    fn call(&mut self, argc: usize, names: &[Rc<str>]) -> Result<(), ()> {
+        let Some(slots) = self.stack.len().checked_sub(argc + 1) else {
+            return self.error("stack underflow");
+        };
+        let function = match self.stack[slots].clone() {
+            Value::Function(function) => function,
+            Value::Native(native) => return self.call_native(native, argc, names),
+            Value::Weak(weak) if argc == 0 => {
+                self.stack.truncate(slots);
+                return self.push(weak.upgrade().unwrap_or_default());
             }
+            _ => return self.error("only functions can be called"),
+        };
+        if self.frames.len() == self.config.max_frames {
+            return self.fail(ErrorKind::StackOverflow);
+        }
+
+        let args = self.pop_n(argc)?;
+        let args = match function.bind_arguments(args, names) {
+            Ok(args) => args,
+            Err(err) => return self.error(err),
+        };
+        for arg in args {
+            self.push(arg)?;
+        }
+        if function.variadic {
+            let rest = self.peek(0)?.clone();
+            self.heap.track(&rest);
+            self.check_heap_limit()?;
+        }
+
+        self.frames.push(CallFrame {
+            function,
+            instruction_pointer: 0,
+            slots,
+            finalizer: false,
+        });
+
+        Ok(())
+    }
+
+    fn call_native(&mut self, native: Native, argc: usize, names: &[Rc<str>]) -> Result<(), ()> {
+        if !names.is_empty() {
+            return self.error(format!("{}() takes no named arguments", native.name));
+        }
+        if argc != native.arity {
+            return self.error(format!(
+                "{}() takes {} arguments but {argc} were given",
+                native.name, native.arity
+            ));
+        }
+
+        let args = self.pop_n(argc)?;
+        self.pop()?;
+        match (native.function)(&mut self.heap, &args) {
+            Ok(result) => self.allocate(result),
+            Err(message) => self.error(message),
         }
     }
 
-    fn push(&mut self, value: Value) {
-        self.stack[self.stack_top] = value;
-        self.stack_top += 1;
+    /// Throws a runtime error, which scripts can catch.
+    fn error<T>(&mut self, message: impl Display) -> Result<T, ()> {
+        let thrown = Value::from(message.to_string().as_str());
+        self.heap.track(&thrown);
+        self.thrown = Some(thrown);
+        Err(())
     }
 
-    fn pop(&mut self) -> Value {
-        self.stack_top -= 1;
-        self.stack[self.stack_top]
+    /// Throws the `RuntimeError` variant for `kind`, which scripts can catch
+    /// and match on, and which makes an error of that kind if they don't.
+    fn fail<T>(&mut self, kind: ErrorKind) -> Result<T, ()> {
+        let variant = kind.variant().expect("scripts can catch the error");
+        let thrown = Value::new_variant(Rc::new(variant), Vec::new());
+        self.heap.track(&thrown);
+        self.thrown = Some(thrown);
+        Err(())
     }
 
-    fn binary_op(&mut self, op: fn(Value, Value) -> Value) {
-        let b = self.pop();
-        let a = self.pop();
+    /// Stops the script because of an error at `offset` in the running
+    /// frame, which is described along with the calls that led to it.
+    fn abort(&mut self, kind: ErrorKind, message: String, offset: usize) -> RuntimeError {
+        let trace: Vec<_> = self
+            .frames
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(i, frame)| {
+                // Callers are stopped right after their call instruction.
+                let offset = match i {
+                    0 => offset,
+                    _ => frame.instruction_pointer.saturating_sub(1),
+                };
+                TraceFrame {
+                    function: frame.function.name.clone(),
+                    line: frame.function.chunk.line_for_offset(offset),
+                    column: frame.function.chunk.column_for_offset(offset),
+                }
+            })
+            .collect();
 
-        self.push(op(a, b))
+        self.frames.clear();
+        self.handlers.clear();
+        RuntimeError {
+            kind,
+            message,
+            line: trace.first().map_or(0, |frame| frame.line),
+            column: trace.first().map_or(0, |frame| frame.column),
+            trace,
+        }
     }
 
-    fn read_op(&mut self) -> OpCode {
-        let instruction = self.chunk.code[self.instruction_pointer]
-            .try_into()
-            .unwrap();
-        self.instruction_pointer += 1;
-        instruction
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("a frame is running")
     }
 
-    fn read_constant(&mut self) -> Value {
-        let const_index = self.chunk.code[self.instruction_pointer];
-        self.instruction_pointer += 1;
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("a frame is running")
+    }
 
-        self.chunk.constants[const_index as usize]
+    /// Reads the next instruction, making sure its operands are there too.
+    fn read_op(&mut self) -> Result<OpCode, ()> {
+        let frame = self.frame();
+        let Some(&byte) = frame.function.chunk.code.get(frame.instruction_pointer) else {
+            return self.error("reached the end of the code without returning");
+        };
+        let Ok(op) = OpCode::try_from(byte) else {
+            return self.error(format!("unknown opcode {byte}"));
+        };
+        if frame.instruction_pointer + op.num_operands() >= frame.function.chunk.code.len() {
+            return self.error(format!("missing operands for {op}"));
+        }
+
+        self.frame_mut().instruction_pointer += 1;
+        Ok(op)
+    }
+
+    fn read_operand(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let operand = frame.function.chunk.code[frame.instruction_pointer];
+        frame.instruction_pointer += 1;
+        operand
+    }
+
+    /// Reads a local's slot, relative to the running frame.
+    fn read_slot(&mut self) -> usize {
+        self.read_operand() as usize + self.frame().slots
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        let high = self.read_operand();
+        let low = self.read_operand();
+        u16::from_be_bytes([high, low])
+    }
+
+    fn read_u24(&mut self) -> u32 {
+        let high = self.read_operand();
+        let middle = self.read_operand();
+        let low = self.read_operand();
+        u32::from_be_bytes([0, high, middle, low])
+    }
+
+    /// Reads the constant index of `op`, which takes up 24 bits for its
+    /// `_LONG` form.
+    fn read_constant(&mut self, op: OpCode) -> Result<Value, ()> {
+        let const_index = match op.constant_width() {
+            3 => self.read_u24(),
+            _ => self.read_operand() as u32,
+        };
+        self.constant(const_index as usize)
+    }
+
+    fn constant(&mut self, const_index: usize) -> Result<Value, ()> {
+        match self.frame().function.chunk.constants.get(const_index) {
+            Some(constant) => Ok(constant.clone()),
+            None => self.error(format!("invalid constant index {const_index}")),
+        }
+    }
+
+    fn read_string(&mut self, op: OpCode) -> Result<Rc<str>, ()> {
+        match self.read_constant(op)? {
+            Value::String(name) => Ok(name),
+            value => self.error(format!("expected a name constant, found {value}")),
+        }
     }
 }
 
@@ -116,55 +966,90 @@ mod test {
     fn arithmetic() {
         {
             let mut chunk = Chunk::new_named("Negation");
-            let const_offset = chunk.add_constant(42.0);
+            let const_offset = chunk.add_constant(42.0.into());
 
-            chunk.write_operation(OpCode::Constant, [const_offset], 1);
-            chunk.write_operation(OpCode::Negate, [], 1);
-            chunk.write_operation(OpCode::Return, [], 1);
+            chunk.write_operation(OpCode::Constant, [const_offset as u8], 1, 1);
+            chunk.write_operation(OpCode::Negate, [], 1, 1);
+            chunk.write_operation(OpCode::Return, [], 1, 1);
 
             let output = VM::new(chunk).run();
-            assert_eq!(-42.0, output.unwrap());
+            assert_eq!(Value::Float(-42.0), output.unwrap());
         }
 
         {
             let mut chunk = Chunk::new_named("Addition");
-            let a_offset = chunk.add_constant(3.0);
-            let b_offset = chunk.add_constant(1.0);
-            chunk.write_operation(OpCode::Constant, [a_offset], 1);
-            chunk.write_operation(OpCode::Constant, [b_offset], 1);
-            chunk.write_operation(OpCode::Add, [], 1);
-            chunk.write_operation(OpCode::Return, [], 1);
+            let a_offset = chunk.add_constant(Value::Integer(3));
+            let b_offset = chunk.add_constant(Value::Integer(1));
+            chunk.write_operation(OpCode::Constant, [a_offset as u8], 1, 1);
+            chunk.write_operation(OpCode::Constant, [b_offset as u8], 1, 1);
+            chunk.write_operation(OpCode::Add, [], 1, 1);
+            chunk.write_operation(OpCode::Return, [], 1, 1);
 
             let output = VM::new(chunk).run();
 
-            assert_eq!(4.0, output.unwrap());
+            assert_eq!(Value::Integer(4), output.unwrap());
         }
 
-        fn an_expression(a: Value, b: Value, c: Value, d: Value) {
+        fn an_expression(a: f64, b: f64, c: f64, d: f64) {
             let mut chunk = Chunk::new_named("(a + b) * (c - d)");
 
-            let a_offset = chunk.add_constant(a);
-            let b_offset = chunk.add_constant(b);
-            let c_offset = chunk.add_constant(c);
-            let d_offset = chunk.add_constant(d);
+            let a_offset = chunk.add_constant(a.into());
+            let b_offset = chunk.add_constant(b.into());
+            let c_offset = chunk.add_constant(c.into());
+            let d_offset = chunk.add_constant(d.into());
 
-            chunk.write_operation(OpCode::Constant, [a_offset], 1);
-            chunk.write_operation(OpCode::Constant, [b_offset], 1);
-            chunk.write_operation(OpCode::Add, [], 1);
+            chunk.write_operation(OpCode::Constant, [a_offset as u8], 1, 1);
+            chunk.write_operation(OpCode::Constant, [b_offset as u8], 1, 1);
+            chunk.write_operation(OpCode::Add, [], 1, 1);
 
-            chunk.write_operation(OpCode::Constant, [c_offset], 1);
-            chunk.write_operation(OpCode::Constant, [d_offset], 1);
-            chunk.write_operation(OpCode::Subtract, [], 1);
+            chunk.write_operation(OpCode::Constant, [c_offset as u8], 1, 1);
+            chunk.write_operation(OpCode::Constant, [d_offset as u8], 1, 1);
+            chunk.write_operation(OpCode::Subtract, [], 1, 1);
 
-            chunk.write_operation(OpCode::Multiply, [], 1);
+            chunk.write_operation(OpCode::Multiply, [], 1, 1);
 
-            chunk.write_operation(OpCode::Return, [], 1);
+            chunk.write_operation(OpCode::Return, [], 1, 1);
 
             let output = VM::new(chunk).run();
 
-            assert_eq!((a + b) * (c - d), output.unwrap());
+            assert_eq!(Value::Float((a + b) * (c - d)), output.unwrap());
         }
 
         an_expression(1.0, 2.0, 3.0, 5.0);
     }
+
+    #[test]
+    fn malformed_code() {
+        fn error(code: &[u8]) -> String {
+            let mut chunk = Chunk::new_named("Malformed");
+            chunk.add_constant(Value::Integer(1));
+            for &byte in code {
+                chunk.write_operand(byte, 1, 1);
+            }
+
+            VM::new(chunk).run().unwrap_err().message
+        }
+
+        assert_eq!(error(&[255]), "unknown opcode 255");
+        // The script itself is in the first slot.
+        assert_eq!(
+            error(&[OpCode::Pop.into(), OpCode::Pop.into()]),
+            "stack underflow"
+        );
+        assert_eq!(error(&[OpCode::Add.into()]), "stack underflow");
+        assert_eq!(
+            error(&[OpCode::Constant.into(), 7]),
+            "invalid constant index 7"
+        );
+        assert_eq!(
+            error(&[OpCode::Constant.into()]),
+            "missing operands for CONSTANT"
+        );
+        assert_eq!(error(&[OpCode::GetLocal.into(), 9]), "invalid local slot");
+        assert_eq!(error(&[OpCode::Loop.into(), 0, 9]), "jump out of bounds");
+        assert_eq!(
+            error(&[OpCode::Nil.into()]),
+            "reached the end of the code without returning"
+        );
+    }
 }
@@ -0,0 +1,114 @@
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak},
+};
+
+use super::{function::Function, value::EnumValue, value::Value};
+
+/// A reference to a heap object that doesn't keep it alive.
+#[derive(Debug, Clone)]
+pub enum WeakRef {
+    List(Weak<RefCell<Vec<Value>>>),
+    Tuple(Weak<[Value]>),
+    Enum(Weak<EnumValue>),
+    Function(Weak<Function>),
+    WeakMap(Weak<RefCell<WeakMap>>),
+}
+
+impl WeakRef {
+    /// `None` if `value` isn't an object.
+    pub fn new(value: &Value) -> Option<Self> {
+        Some(match value {
+            Value::List(list) => WeakRef::List(Rc::downgrade(list)),
+            Value::Tuple(tuple) => WeakRef::Tuple(Rc::downgrade(tuple)),
+            Value::Enum(value) => WeakRef::Enum(Rc::downgrade(value)),
+            Value::Function(function) => WeakRef::Function(Rc::downgrade(function)),
+            Value::WeakMap(map) => WeakRef::WeakMap(Rc::downgrade(map)),
+            _ => return None,
+        })
+    }
+
+    /// The object, unless it has been freed.
+    pub fn upgrade(&self) -> Option<Value> {
+        Some(match self {
+            WeakRef::List(list) => Value::List(list.upgrade()?),
+            WeakRef::Tuple(tuple) => Value::Tuple(tuple.upgrade()?),
+            WeakRef::Enum(value) => Value::Enum(value.upgrade()?),
+            WeakRef::Function(function) => Value::Function(function.upgrade()?),
+            WeakRef::WeakMap(map) => Value::WeakMap(map.upgrade()?),
+        })
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.upgrade().is_some()
+    }
+
+    /// Where the object is or was, which identifies it while it's alive.
+    pub fn address(&self) -> usize {
+        match self {
+            WeakRef::List(list) => list.as_ptr() as *const () as usize,
+            WeakRef::Tuple(tuple) => tuple.as_ptr() as *const () as usize,
+            WeakRef::Enum(value) => value.as_ptr() as *const () as usize,
+            WeakRef::Function(function) => function.as_ptr() as *const () as usize,
+            WeakRef::WeakMap(map) => map.as_ptr() as *const () as usize,
+        }
+    }
+
+    fn refers_to(&self, value: &Value) -> bool {
+        WeakRef::new(value).is_some_and(|other| other.address() == self.address())
+            && self.is_alive()
+    }
+}
+
+/// Weak references are equal when they refer to the same object.
+impl PartialEq for WeakRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.address() == other.address()
+    }
+}
+
+/// A map from objects to values which doesn't keep its keys alive. An entry
+/// goes away once nothing but its own value refers to its key.
+#[derive(Debug, Default, PartialEq)]
+pub struct WeakMap {
+    pub entries: Vec<(WeakRef, Value)>,
+}
+
+impl WeakMap {
+    /// `None` if `key` isn't an object.
+    pub fn get(&self, key: &Value) -> Option<Value> {
+        WeakRef::new(key)?;
+        Some(
+            self.entries
+                .iter()
+                .find(|(entry, _)| entry.refers_to(key))
+                .map_or(Value::Nil, |(_, value)| value.clone()),
+        )
+    }
+
+    /// `None` if `key` isn't an object.
+    pub fn insert(&mut self, key: &Value, value: Value) -> Option<()> {
+        self.entries.retain(|(key, _)| key.is_alive());
+        match self
+            .entries
+            .iter_mut()
+            .find(|(entry, _)| entry.refers_to(key))
+        {
+            Some((_, entry)) => *entry = value,
+            None => self.entries.push((WeakRef::new(key)?, value)),
+        }
+        Some(())
+    }
+
+    /// How many of the keys are still alive.
+    pub fn len(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|(key, _)| key.is_alive())
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}